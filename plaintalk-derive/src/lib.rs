@@ -0,0 +1,236 @@
+/*!
+Derive macros for the `FromMessage` and `ToMessage` traits in
+`plaintalk::command`, mapping the variants of an enum to the verbs of a
+protocol:
+
+```ignore
+#[macro_use]
+extern crate plaintalk_derive;
+
+#[derive(FromMessage, ToMessage)]
+enum Command {
+    Protocol(String),
+    #[plaintalk(verb = "get")]
+    Fetch { key: String, default: Option<String> },
+    Delete(#[plaintalk(varargs)] Vec<String>),
+}
+```
+
+The verb is the name of the variant in snake case unless it is given with
+`#[plaintalk(verb = "...")]`. The fields of the variant are the arguments.
+Fields of type `Option` may be left out at the end of a message, and the
+last field may be marked with `#[plaintalk(varargs)]` to collect all the
+remaining fields of the message.
+*/
+
+extern crate proc_macro;
+extern crate proc_macro2;
+#[macro_use]
+extern crate quote;
+extern crate syn;
+
+use proc_macro2::{Span, TokenStream};
+use syn::{Data, DeriveInput, Error, Fields, Ident, LitByteStr, LitStr, Type};
+
+enum Kind {
+	Required,
+	Optional,
+	Varargs,
+}
+
+struct Argument {
+	name: Option<Ident>,
+	kind: Kind,
+}
+
+struct Variant {
+	ident: Ident,
+	verb: String,
+	named: bool,
+	arguments: Vec<Argument>,
+}
+
+#[proc_macro_derive(FromMessage, attributes(plaintalk))]
+pub fn derive_from_message(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+	let input = syn::parse_macro_input!(input as DeriveInput);
+	match variants(&input) {
+		Ok(variants) => from_message(&input, &variants),
+		Err(err) => err.into_compile_error(),
+	}.into()
+}
+
+#[proc_macro_derive(ToMessage, attributes(plaintalk))]
+pub fn derive_to_message(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+	let input = syn::parse_macro_input!(input as DeriveInput);
+	match variants(&input) {
+		Ok(variants) => to_message(&input, &variants),
+		Err(err) => err.into_compile_error(),
+	}.into()
+}
+
+fn snake_case(ident: &Ident) -> String {
+	let mut verb = String::new();
+	for (i, c) in ident.to_string().chars().enumerate() {
+		if c.is_uppercase() && i > 0 {
+			verb.push('_');
+		}
+		verb.extend(c.to_lowercase());
+	}
+	verb
+}
+
+fn is_option(ty: &Type) -> bool {
+	match *ty {
+		Type::Path(ref path) if path.qself.is_none() =>
+			path.path.segments.last().map(|segment| segment.ident == "Option").unwrap_or(false),
+		_ => false,
+	}
+}
+
+fn variants(input: &DeriveInput) -> Result<Vec<Variant>, Error> {
+	let data = match input.data {
+		Data::Enum(ref data) => data,
+		_ => return Err(Error::new_spanned(&input.ident, "FromMessage and ToMessage can only be derived for enums")),
+	};
+
+	let mut variants: Vec<Variant> = Vec::new();
+	for variant in &data.variants {
+		let mut verb = snake_case(&variant.ident);
+		for attr in variant.attrs.iter().filter(|attr| attr.path().is_ident("plaintalk")) {
+			try!{attr.parse_nested_meta(|meta| {
+				if meta.path.is_ident("verb") {
+					let value: LitStr = try!{try!{meta.value()}.parse()};
+					verb = value.value();
+					Ok(())
+				} else {
+					Err(meta.error("Expected `verb = \"...\"`"))
+				}
+			})};
+		}
+		if variants.iter().any(|other| other.verb == verb) {
+			return Err(Error::new_spanned(&variant.ident, format!("Duplicate verb `{}`", verb)));
+		}
+
+		let mut arguments = Vec::new();
+		for (i, field) in variant.fields.iter().enumerate() {
+			let mut varargs = false;
+			for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("plaintalk")) {
+				try!{attr.parse_nested_meta(|meta| {
+					if meta.path.is_ident("varargs") {
+						varargs = true;
+						Ok(())
+					} else {
+						Err(meta.error("Expected `varargs`"))
+					}
+				})};
+			}
+			if varargs && i != variant.fields.len() - 1 {
+				return Err(Error::new_spanned(field, "Only the last field can be varargs"));
+			}
+			arguments.push(Argument {
+				name: field.ident.clone(),
+				kind: if varargs {
+					Kind::Varargs
+				} else if is_option(&field.ty) {
+					Kind::Optional
+				} else {
+					Kind::Required
+				},
+			});
+		}
+
+		variants.push(Variant {
+			ident: variant.ident.clone(),
+			verb: verb,
+			named: match variant.fields { Fields::Named(_) => true, _ => false },
+			arguments: arguments,
+		});
+	}
+	Ok(variants)
+}
+
+fn binding(i: usize) -> Ident {
+	Ident::new(&format!("__field{}", i), Span::call_site())
+}
+
+fn from_message(input: &DeriveInput, variants: &[Variant]) -> TokenStream {
+	let ident = &input.ident;
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+	let arms = variants.iter().map(|variant| {
+		let verb = LitByteStr::new(variant.verb.as_bytes(), variant.ident.span());
+		let variant_ident = &variant.ident;
+		let reads = variant.arguments.iter().map(|argument| match argument.kind {
+			Kind::Required => quote! { ::plaintalk::command::read_argument(message)? },
+			Kind::Optional => quote! { ::plaintalk::command::read_optional(message)? },
+			Kind::Varargs => quote! { ::plaintalk::command::read_varargs(message)? },
+		});
+		let value = if variant.named {
+			let names = variant.arguments.iter().map(|argument| &argument.name);
+			quote! { #ident::#variant_ident { #(#names: #reads),* } }
+		} else if variant.arguments.is_empty() {
+			quote! { #ident::#variant_ident }
+		} else {
+			quote! { #ident::#variant_ident(#(#reads),*) }
+		};
+		quote! { #verb => #value, }
+	});
+
+	quote! {
+		impl #impl_generics ::plaintalk::command::FromMessage for #ident #ty_generics #where_clause {
+			fn read_fields(message: &mut ::plaintalk::pullparser::Message) -> ::std::result::Result<Self, ::plaintalk::pullparser::Error> {
+				let verb = ::plaintalk::command::read_verb(message)?;
+				let value = match &verb[..] {
+					#(#arms)*
+					_ => return ::std::result::Result::Err(::plaintalk::pullparser::Error::Unspecified("Unknown verb")),
+				};
+				::plaintalk::command::expect_end(message)?;
+				::std::result::Result::Ok(value)
+			}
+		}
+	}
+}
+
+fn to_message(input: &DeriveInput, variants: &[Variant]) -> TokenStream {
+	let ident = &input.ident;
+	let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+	let arms = variants.iter().map(|variant| {
+		let verb = LitByteStr::new(variant.verb.as_bytes(), variant.ident.span());
+		let variant_ident = &variant.ident;
+		let bindings: Vec<Ident> = (0..variant.arguments.len()).map(binding).collect();
+		let pattern = if variant.named {
+			let names = variant.arguments.iter().map(|argument| &argument.name);
+			quote! { #ident::#variant_ident { #(#names: ref #bindings),* } }
+		} else if variant.arguments.is_empty() {
+			quote! { #ident::#variant_ident }
+		} else {
+			quote! { #ident::#variant_ident(#(ref #bindings),*) }
+		};
+		let writes = variant.arguments.iter().zip(bindings.iter()).map(|(argument, binding)| match argument.kind {
+			Kind::Required | Kind::Optional => quote! { message.write(#binding)?; },
+			Kind::Varargs => quote! {
+				for value in #binding {
+					message.write(value)?;
+				}
+			},
+		});
+		quote! {
+			#pattern => {
+				message.write_field(#verb)?;
+				#(#writes)*
+			},
+		}
+	});
+
+	quote! {
+		impl #impl_generics ::plaintalk::command::ToMessage for #ident #ty_generics #where_clause {
+			fn write_fields<W: ::std::io::Write>(&self, message: &mut ::plaintalk::pushgenerator::Message<W>) -> ::std::result::Result<(), ::plaintalk::pushgenerator::Error> {
+				match *self {
+					#(#arms)*
+				}
+				::std::result::Result::Ok(())
+			}
+		}
+	}
+}
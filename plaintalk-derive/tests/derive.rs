@@ -0,0 +1,70 @@
+extern crate plaintalk;
+#[macro_use]
+extern crate plaintalk_derive;
+
+use std::io::Cursor;
+
+use plaintalk::command::{FromMessage, ToMessage};
+use plaintalk::pullparser::{self, PullParser};
+use plaintalk::pushgenerator::PushGenerator;
+
+#[derive(Debug, PartialEq, FromMessage, ToMessage)]
+enum Command {
+	Protocol(String),
+	Ping,
+	#[plaintalk(verb = "get")]
+	Fetch { key: String, default: Option<String> },
+	Delete(#[plaintalk(varargs)] Vec<String>),
+	SetLimit(u32, bool),
+}
+
+fn parse(data: &[u8]) -> Result<(u32, Command), pullparser::Error> {
+	let mut parser = PullParser::new(Cursor::new(data));
+	let mut message = try!{parser.get_message()}.unwrap();
+	let id = try!{message.read()}.unwrap();
+	let command = try!{Command::read_fields(&mut message)};
+	Ok((id, command))
+}
+
+fn generate(id: u32, command: &Command) -> Vec<u8> {
+	let mut buffer = Vec::new();
+	{
+		let mut generator = PushGenerator::new(&mut buffer);
+		let mut message = generator.next_message().unwrap();
+		message.write(&id).unwrap();
+		command.write_fields(&mut message).unwrap();
+	}
+	buffer
+}
+
+#[test]
+fn it_round_trips() {
+	let cases: Vec<(Command, &[u8])> = vec![
+		(Command::Protocol("lol".into()), b"0 protocol lol\n"),
+		(Command::Ping, b"0 ping\n"),
+		(Command::Fetch { key: "a b".into(), default: Some("c".into()) }, b"0 get {3}a b c\n"),
+		(Command::Fetch { key: "a".into(), default: None }, b"0 get a {0}\n"),
+		(Command::Delete(vec![]), b"0 delete\n"),
+		(Command::Delete(vec!["a".into(), "b".into()]), b"0 delete a b\n"),
+		(Command::SetLimit(10, true), b"0 set_limit 10 true\n"),
+	];
+	for (command, encoded) in cases {
+		assert_eq!(encoded, &generate(0, &command)[..]);
+		assert_eq!((0, command), parse(encoded).unwrap());
+	}
+}
+
+#[test]
+fn it_reads_optional_arguments_that_are_left_out() {
+	assert_eq!((5, Command::Fetch { key: "a".into(), default: None }), parse(b"5 get a\n").unwrap());
+}
+
+#[test]
+fn it_rejects_invalid_commands() {
+	assert!(parse(b"0 fetch a\n").is_err());
+	assert!(parse(b"0\n").is_err());
+	assert!(parse(b"0 get\n").is_err());
+	assert!(parse(b"0 ping pong\n").is_err());
+	assert!(parse(b"0 set_limit 10\n").is_err());
+	assert!(parse(b"0 set_limit ten true\n").is_err());
+}
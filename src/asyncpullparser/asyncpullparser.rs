@@ -0,0 +1,148 @@
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_io::AsyncBufRead;
+
+use message::{OwnedMessage, MessageBuf};
+use pullparser::Error;
+use super::field::*;
+use super::message::*;
+
+pub enum PullParserState {
+	Initial,
+	Done,
+	Error(&'static str),
+}
+
+pub struct AsyncPullParser<R> {
+	inner: R,
+	state: PullParserState,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncPullParser<R> {
+	pub fn new(inner: R) -> AsyncPullParser<R> {
+		AsyncPullParser {
+			inner: inner,
+			state: PullParserState::Initial,
+		}
+	}
+
+	pub fn get_message<'x>(&'x mut self) -> Result<Option<Message<'x, R>>, Error> {
+		match self.state {
+			PullParserState::Initial => Ok(Some(Message::new(&mut self.inner, &mut self.state))),
+			PullParserState::Done => Ok(None),
+			PullParserState::Error(err) => Err(Error::Unspecified(err)),
+		}
+	}
+
+	/// Read the next message into `buf`, replacing its contents. The future
+	/// resolves to `false` at the end of the stream.
+	pub fn read_message_into<'x>(&'x mut self, buf: &'x mut MessageBuf) -> ReadMessageInto<'x, R> {
+		buf.clear();
+		ReadMessageInto {
+			reader: MessageReader::new(self.get_message()),
+			buf: buf,
+		}
+	}
+
+	pub fn read_owned_message<'x>(&'x mut self) -> ReadOwnedMessage<'x, R> {
+		ReadOwnedMessage {
+			reader: MessageReader::new(self.get_message()),
+			buf: OwnedMessage::new(),
+		}
+	}
+}
+
+struct MessageReader<'a, R: 'a> {
+	message: Option<Result<Option<Message<'a, R>>, Error>>,
+	field_state: Option<FieldParserState>,
+}
+
+impl<'a, R: AsyncBufRead + Unpin> MessageReader<'a, R> {
+	fn new(message: Result<Option<Message<'a, R>>, Error>) -> MessageReader<'a, R> {
+		MessageReader {
+			message: Some(message),
+			field_state: None,
+		}
+	}
+
+	fn poll_read_into(&mut self, cx: &mut Context, buf: &mut MessageBuf) -> Poll<Result<bool, Error>> {
+		let message = match self.message {
+			Some(Ok(Some(ref mut message))) => message,
+			Some(Ok(None)) => return Poll::Ready(Ok(false)),
+			Some(Err(_)) => match self.message.take() {
+				Some(Err(err)) => return Poll::Ready(Err(err)),
+				_ => unreachable!(),
+			},
+			None => panic!("Message future polled after completion"),
+		};
+
+		loop {
+			if self.field_state.is_none() {
+				match message.start_field() {
+					Ok(Some(field_state)) => self.field_state = Some(field_state),
+					Err(err) => return Poll::Ready(Err(err)),
+					Ok(None) => {
+						// See PullParser::at_eof
+						let at_eof = match *message.parser_state() {
+							PullParserState::Done => buf.len() == 1 && buf[0].len() == 0,
+							_ => false,
+						};
+						if at_eof {
+							buf.clear();
+						}
+						return Poll::Ready(Ok(!at_eof));
+					},
+				}
+			}
+
+			let read = {
+				let field_state = self.field_state.as_mut().unwrap();
+				match message.poll_read_chunk(field_state, cx, buf.field_data_mut()) {
+					Poll::Ready(Ok(read)) => read,
+					Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+					Poll::Pending => return Poll::Pending,
+				}
+			};
+			if read == 0 {
+				buf.end_field();
+				self.field_state = None;
+			}
+		}
+	}
+}
+
+pub struct ReadMessageInto<'a, R: 'a> {
+	reader: MessageReader<'a, R>,
+	buf: &'a mut MessageBuf,
+}
+
+impl<'a, R: AsyncBufRead + Unpin> Future for ReadMessageInto<'a, R> {
+	type Output = Result<bool, Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<bool, Error>> {
+		let this = &mut *self;
+		this.reader.poll_read_into(cx, this.buf)
+	}
+}
+
+pub struct ReadOwnedMessage<'a, R: 'a> {
+	reader: MessageReader<'a, R>,
+	buf: OwnedMessage,
+}
+
+impl<'a, R: AsyncBufRead + Unpin> Future for ReadOwnedMessage<'a, R> {
+	type Output = Result<Option<OwnedMessage>, Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Option<OwnedMessage>, Error>> {
+		let this = &mut *self;
+		match this.reader.poll_read_into(cx, &mut this.buf) {
+			Poll::Ready(Ok(true)) => Poll::Ready(Ok(Some(mem::replace(&mut this.buf, OwnedMessage::new())))),
+			Poll::Ready(Ok(false)) => Poll::Ready(Ok(None)),
+			Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
+			Poll::Pending => Poll::Pending,
+		}
+	}
+}
@@ -0,0 +1,209 @@
+use std::cmp;
+use std::io::{self, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_io::{AsyncBufRead, AsyncRead};
+
+use super::asyncpullparser::*;
+use super::message::*;
+
+const CURLY_L: u8 = b'{';
+const CURLY_R: u8 = b'}';
+const SP: u8 = b' ';
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUM_0: u8 = b'0';
+const NUM_9: u8 = b'9';
+
+#[derive(Clone, Copy)]
+pub enum FieldParserState {
+	Initial,
+	ReadingEscapeHeader(usize),
+	ReadingEscapedBytes(usize),
+	ExpectingLineFeed,
+	Done,
+	Error(ErrorKind, &'static str)
+}
+
+pub struct Field<'a, R: 'a> {
+	inner: &'a mut R,
+	parser_state: &'a mut PullParserState,
+	message_state: &'a mut MessageParserState,
+	empty: &'a mut bool,
+	state: &'a mut FieldParserState,
+}
+
+impl<'a, R: AsyncBufRead + Unpin> Field<'a, R> {
+	pub(super) fn new(
+		inner: &'a mut R,
+		parser_state: &'a mut PullParserState,
+		message_state: &'a mut MessageParserState,
+		empty: &'a mut bool,
+		state: &'a mut FieldParserState,
+	) -> Field<'a, R> {
+		*state = FieldParserState::Initial;
+		Field {
+			inner: inner,
+			parser_state: parser_state,
+			message_state: message_state,
+			empty: empty,
+			state: state,
+		}
+	}
+}
+
+impl<'a, R: AsyncBufRead + Unpin> AsyncRead for Field<'a, R> {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		let this = &mut *self;
+		poll_read_field(this.inner, this.parser_state, this.message_state, this.empty, this.state, cx, buf)
+	}
+}
+
+fn fail(
+	parser_state: &mut PullParserState,
+	message_state: &mut MessageParserState,
+	state: &mut FieldParserState,
+	kind: ErrorKind,
+	err: &'static str,
+) {
+	*parser_state = PullParserState::Error(err);
+	*message_state = MessageParserState::Error(err);
+	*state = FieldParserState::Error(kind, err);
+}
+
+/// The field parser proper, shared by `Field` and the futures that read
+/// several fields without handing out a `Field`.
+pub(super) fn poll_read_field<R: AsyncBufRead + Unpin>(
+	reader: &mut R,
+	parser_state: &mut PullParserState,
+	message_state: &mut MessageParserState,
+	empty: &mut bool,
+	state: &mut FieldParserState,
+	cx: &mut Context,
+	buf: &mut [u8],
+) -> Poll<io::Result<usize>> {
+	let mut cursor: usize = 0;
+
+	while cursor < buf.len() {
+		match *state {
+			FieldParserState::Done => break,
+			FieldParserState::Error(kind, err) => {
+				if cursor > 0 {
+					break;
+				} else {
+					return Poll::Ready(Err(io::Error::new(kind, err)));
+				}
+			},
+			_ => {},
+		}
+
+		let consumed = {
+			let available = match Pin::new(&mut *reader).poll_fill_buf(cx) {
+				Poll::Ready(Ok(available)) => available,
+				Poll::Ready(Err(err)) => {
+					// TODO Maybe put the whole parser in an error state?
+					if cursor > 0 { break; }
+					return Poll::Ready(Err(err));
+				},
+				Poll::Pending => {
+					if cursor > 0 { break; }
+					return Poll::Pending;
+				},
+			};
+
+			if available.len() == 0 {
+				match *state {
+					FieldParserState::Initial if *empty && cursor == 0 => {
+						*parser_state = PullParserState::Done;
+						*message_state = MessageParserState::Done;
+						*state = FieldParserState::Done;
+					},
+					_ => fail(parser_state, message_state, state, ErrorKind::InvalidData, "Unexpected EOF"),
+				}
+				continue;
+			}
+
+			let mut consumed = 0;
+			while consumed < available.len() && cursor < buf.len() {
+				match *state {
+					FieldParserState::Initial => {
+						let byte = available[consumed];
+						consumed += 1;
+						match byte {
+							CURLY_L => *state = FieldParserState::ReadingEscapeHeader(0),
+							SP => {
+								// A field separator means this is not an empty line
+								*empty = false;
+								*message_state = MessageParserState::ExpectingField;
+								*state = FieldParserState::Done;
+							},
+							LF => {
+								if !(*empty && cursor == 0) {
+									*message_state = MessageParserState::Done;
+									*state = FieldParserState::Done;
+								}
+							},
+							CR => *state = FieldParserState::ExpectingLineFeed,
+							ch => {
+								buf[cursor] = ch;
+								cursor += 1;
+							},
+						}
+					},
+					FieldParserState::ReadingEscapeHeader(escaped_bytes) => {
+						let byte = available[consumed];
+						consumed += 1;
+						match byte {
+							CURLY_R => {
+								*state = match escaped_bytes {
+									0 => FieldParserState::Initial,
+									n => FieldParserState::ReadingEscapedBytes(n),
+								};
+							},
+							x if NUM_0 <= x && x <= NUM_9 => {
+								match escaped_bytes.checked_mul(10).and_then(|y| y.checked_add((x - NUM_0) as usize)) {
+									Some(y) => *state = FieldParserState::ReadingEscapeHeader(y),
+									None => fail(parser_state, message_state, state, ErrorKind::InvalidData, "Overflow in PlainTalk escape sequence"),
+								}
+							},
+							_ => fail(parser_state, message_state, state, ErrorKind::InvalidData, "Invalid symbol in PlainTalk escape sequence"),
+						}
+					},
+					FieldParserState::ReadingEscapedBytes(size_left) => {
+						let len = cmp::min(cmp::min(size_left, available.len() - consumed), buf.len() - cursor);
+						buf[cursor..cursor + len].copy_from_slice(&available[consumed..consumed + len]);
+						cursor += len;
+						consumed += len;
+						*state = match size_left - len {
+							0 => FieldParserState::Initial,
+							x => FieldParserState::ReadingEscapedBytes(x),
+						};
+					},
+					FieldParserState::ExpectingLineFeed => {
+						let byte = available[consumed];
+						consumed += 1;
+						match byte {
+							LF => {
+								if !(*empty && cursor == 0) {
+									*message_state = MessageParserState::Done;
+									*state = FieldParserState::Done;
+								} else {
+									*state = FieldParserState::Initial;
+								}
+							},
+							_ => fail(parser_state, message_state, state, ErrorKind::InvalidData, "Invalid byte after CR"),
+						}
+					},
+					FieldParserState::Done | FieldParserState::Error(..) => break,
+				}
+			}
+			consumed
+		};
+
+		Pin::new(&mut *reader).consume(consumed);
+	}
+
+	*empty = *empty && (cursor == 0);
+	Poll::Ready(Ok(cursor))
+}
@@ -0,0 +1,192 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_io::{AsyncBufRead, AsyncRead};
+
+use pullparser::Error;
+use super::asyncpullparser::*;
+use super::field::*;
+
+pub enum MessageParserState {
+	ExpectingField,
+	ReadingField,
+	Done,
+	Error(&'static str),
+}
+
+pub struct Message<'a, R: 'a> {
+	inner: &'a mut R,
+	parser_state: &'a mut PullParserState,
+	state: MessageParserState,
+	empty: bool,
+	// The state of the current field, kept here so that `ignore_rest` can
+	// skip the rest of a partially read field after the `Field` is gone
+	field_state: FieldParserState,
+}
+
+impl<'a, R: AsyncBufRead + Unpin> Message<'a, R> {
+	pub(super) fn new(inner: &'a mut R, parser_state: &'a mut PullParserState) -> Message<'a, R> {
+		Message {
+			inner: inner,
+			parser_state: parser_state,
+			state: MessageParserState::ExpectingField,
+			empty: true,
+			field_state: FieldParserState::Initial,
+		}
+	}
+
+	pub fn get_field<'x>(&'x mut self) -> Result<Option<Field<'x, R>>, Error> {
+		match self.state {
+			MessageParserState::ExpectingField => {
+				self.state = MessageParserState::ReadingField;
+				Ok(Some(Field::new(self.inner, self.parser_state, &mut self.state, &mut self.empty, &mut self.field_state)))
+			},
+			MessageParserState::ReadingField => Err(Error::Unspecified("You need to finish reading the field")),
+			MessageParserState::Done => Ok(None),
+			MessageParserState::Error(err) => Err(Error::Unspecified(err)),
+		}
+	}
+
+	/// Skip the rest of the message, including the field currently being
+	/// read, if any.
+	pub fn ignore_rest<'x>(&'x mut self) -> IgnoreRest<'x, 'a, R> {
+		IgnoreRest {
+			message: self,
+		}
+	}
+
+	/// Read the next field, appending it to `buf`. The future resolves to
+	/// the length of the field, or `None` at the end of the message.
+	pub fn read_field_to_end<'x>(&'x mut self, buf: &'x mut Vec<u8>) -> ReadFieldToEnd<'x, R> {
+		ReadFieldToEnd {
+			field: Some(self.get_field()),
+			buf: buf,
+			len: 0,
+		}
+	}
+
+	pub fn at_end(&self) -> bool {
+		match self.state {
+			MessageParserState::Done => true,
+			_ => false
+		}
+	}
+
+	/// Read one chunk of the current field into `buf`, which is grown as
+	/// necessary. Resolves to 0 at the end of the field.
+	pub(super) fn poll_read_chunk(
+		&mut self,
+		field_state: &mut FieldParserState,
+		cx: &mut Context,
+		buf: &mut Vec<u8>,
+	) -> Poll<Result<usize, Error>> {
+		let len = buf.len();
+		buf.resize(len + READ_CHUNK_SIZE, 0);
+		let result = poll_read_field(self.inner, self.parser_state, &mut self.state, &mut self.empty, field_state, cx, &mut buf[len..]);
+		match result {
+			Poll::Ready(Ok(read)) => {
+				buf.truncate(len + read);
+				Poll::Ready(Ok(read))
+			},
+			Poll::Ready(Err(err)) => {
+				buf.truncate(len);
+				Poll::Ready(Err(Error::Io(err)))
+			},
+			Poll::Pending => {
+				buf.truncate(len);
+				Poll::Pending
+			},
+		}
+	}
+
+	pub(super) fn start_field(&mut self) -> Result<Option<FieldParserState>, Error> {
+		match self.state {
+			MessageParserState::ExpectingField => {
+				self.state = MessageParserState::ReadingField;
+				Ok(Some(FieldParserState::Initial))
+			},
+			MessageParserState::ReadingField => Err(Error::Unspecified("You need to finish reading the field")),
+			MessageParserState::Done => Ok(None),
+			MessageParserState::Error(err) => Err(Error::Unspecified(err)),
+		}
+	}
+
+	pub(super) fn parser_state(&self) -> &PullParserState {
+		self.parser_state
+	}
+}
+
+const READ_CHUNK_SIZE: usize = 256;
+
+pub struct IgnoreRest<'x, 'a: 'x, R: 'a> {
+	message: &'x mut Message<'a, R>,
+}
+
+impl<'x, 'a, R: AsyncBufRead + Unpin> Future for IgnoreRest<'x, 'a, R> {
+	type Output = Result<(), Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+		let message = &mut *self.message;
+		let mut buf = [0u8; READ_CHUNK_SIZE];
+		loop {
+			// Continue where the current field was left, if any
+			match message.state {
+				MessageParserState::ReadingField => {},
+				_ => match message.start_field() {
+					Ok(Some(field_state)) => message.field_state = field_state,
+					Ok(None) => return Poll::Ready(Ok(())),
+					Err(err) => return Poll::Ready(Err(err)),
+				},
+			}
+			match poll_read_field(message.inner, message.parser_state, &mut message.state, &mut message.empty, &mut message.field_state, cx, &mut buf) {
+				Poll::Ready(Ok(_)) => {},
+				Poll::Ready(Err(err)) => return Poll::Ready(Err(Error::Io(err))),
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+	}
+}
+
+pub struct ReadFieldToEnd<'x, R: 'x> {
+	field: Option<Result<Option<Field<'x, R>>, Error>>,
+	buf: &'x mut Vec<u8>,
+	len: usize,
+}
+
+impl<'x, R: AsyncBufRead + Unpin> Future for ReadFieldToEnd<'x, R> {
+	type Output = Result<Option<usize>, Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<Option<usize>, Error>> {
+		let this = &mut *self;
+		let mut field = match this.field.take() {
+			Some(Ok(Some(field))) => field,
+			Some(Ok(None)) => return Poll::Ready(Ok(None)),
+			Some(Err(err)) => return Poll::Ready(Err(err)),
+			None => panic!("ReadFieldToEnd polled after completion"),
+		};
+		loop {
+			let len = this.buf.len();
+			this.buf.resize(len + READ_CHUNK_SIZE, 0);
+			match Pin::new(&mut field).poll_read(cx, &mut this.buf[len..]) {
+				Poll::Ready(Ok(0)) => {
+					this.buf.truncate(len);
+					return Poll::Ready(Ok(Some(this.len)));
+				},
+				Poll::Ready(Ok(read)) => {
+					this.buf.truncate(len + read);
+					this.len += read;
+				},
+				Poll::Ready(Err(err)) => {
+					this.buf.truncate(len);
+					return Poll::Ready(Err(Error::from(err)));
+				},
+				Poll::Pending => {
+					this.buf.truncate(len);
+					this.field = Some(Ok(Some(field)));
+					return Poll::Pending;
+				},
+			}
+		}
+	}
+}
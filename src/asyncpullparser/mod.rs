@@ -0,0 +1,19 @@
+/*!
+An asynchronous counterpart of `pullparser`, for any executor, over
+`futures_io::AsyncBufRead`. Available with the `async` feature.
+
+As with `PullParser`, messages and fields are read one at a time, and each
+`Field` implements `AsyncRead`, so huge fields can be consumed incrementally.
+*/
+
+mod asyncpullparser;
+mod message;
+mod field;
+
+pub use pullparser::Error;
+pub use self::asyncpullparser::{AsyncPullParser, ReadMessageInto, ReadOwnedMessage};
+pub use self::message::{Message, ReadFieldToEnd, IgnoreRest};
+pub use self::field::Field;
+
+#[cfg(test)]
+mod test;
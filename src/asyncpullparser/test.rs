@@ -0,0 +1,160 @@
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use futures::executor::block_on;
+use futures::io::{AsyncRead, AsyncReadExt, BufReader, Cursor};
+use message::{OwnedMessage, MessageBuf};
+use super::*;
+
+// Yields one byte per read, and is pending every other time it is polled
+struct Trickle {
+	data: Vec<u8>,
+	position: usize,
+	pending: bool,
+}
+
+impl Trickle {
+	fn new(data: &[u8]) -> BufReader<Trickle> {
+		BufReader::with_capacity(2, Trickle { data: data.to_vec(), position: 0, pending: true })
+	}
+}
+
+impl AsyncRead for Trickle {
+	fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<io::Result<usize>> {
+		self.pending = !self.pending;
+		if self.pending {
+			cx.waker().wake_by_ref();
+			return Poll::Pending;
+		}
+		if self.position == self.data.len() || buf.len() == 0 {
+			return Poll::Ready(Ok(0));
+		}
+		buf[0] = self.data[self.position];
+		self.position += 1;
+		Poll::Ready(Ok(1))
+	}
+}
+
+fn read_all_messages<R: futures::io::AsyncBufRead + Unpin>(parser: &mut AsyncPullParser<R>) -> Vec<OwnedMessage> {
+	let mut messages = Vec::new();
+	while let Some(message) = block_on(parser.read_owned_message()).unwrap() {
+		messages.push(message);
+	}
+	messages
+}
+
+#[test]
+fn it_works() {
+	let mut parser = AsyncPullParser::new(Cursor::new(b"0 ape katt\n"));
+
+	let mut message = parser.get_message().unwrap().unwrap();
+	let mut buffer = String::new();
+
+	block_on(message.get_field().unwrap().unwrap().read_to_string(&mut buffer)).unwrap();
+	assert_eq!("0", buffer);
+	buffer.clear();
+
+	block_on(message.get_field().unwrap().unwrap().read_to_string(&mut buffer)).unwrap();
+	assert_eq!("ape", buffer);
+	buffer.clear();
+
+	block_on(message.get_field().unwrap().unwrap().read_to_string(&mut buffer)).unwrap();
+	assert_eq!("katt", buffer);
+	assert!(message.at_end());
+}
+
+#[test]
+fn it_parses_like_the_pull_parser() {
+	let data = b"{6}0{1} a{10}pe katt\nlol fie{3}ld 2\r\n\n{}\n{0} {0}\r\n{} \n2 lol\n";
+	let expected: Vec<OwnedMessage> = vec![
+		"{6}0{1} a{10}pe katt\nlol fie{3}ld 2".parse().unwrap(),
+		"{0} {0}".parse().unwrap(),
+		"{} ".parse().unwrap(),
+		"2 lol".parse().unwrap(),
+	];
+
+	assert_eq!(expected, read_all_messages(&mut AsyncPullParser::new(Cursor::new(&data[..]))));
+	assert_eq!(expected, read_all_messages(&mut AsyncPullParser::new(Trickle::new(data))));
+}
+
+#[test]
+fn it_reads_huge_fields_incrementally() {
+	let mut data = b"0 {100000}".to_vec();
+	data.extend(vec![b'x'; 100000]);
+	data.extend(b" end\n");
+	let mut parser = AsyncPullParser::new(Cursor::new(data));
+
+	let mut message = parser.get_message().unwrap().unwrap();
+	assert_eq!(Some(1), block_on(message.read_field_to_end(&mut Vec::new())).unwrap());
+
+	{
+		let mut field = message.get_field().unwrap().unwrap();
+		let mut chunk = [0u8; 1000];
+		let mut total = 0;
+		loop {
+			let len = block_on(field.read(&mut chunk)).unwrap();
+			if len == 0 { break; }
+			assert!(chunk[0..len].iter().all(|&x| x == b'x'));
+			total += len;
+		}
+		assert_eq!(100000, total);
+	}
+
+	let mut buffer = Vec::new();
+	assert_eq!(Some(3), block_on(message.read_field_to_end(&mut buffer)).unwrap());
+	assert_eq!(b"end".to_vec(), buffer);
+	assert_eq!(None, block_on(message.read_field_to_end(&mut buffer)).unwrap());
+}
+
+#[test]
+fn it_can_ignore_a_message() {
+	let mut parser = AsyncPullParser::new(Trickle::new(b"msg1 {3}a b\nmsg2 msg2field2\n"));
+
+	{
+		let mut message = parser.get_message().unwrap().unwrap();
+		let mut buffer = Vec::new();
+		block_on(message.read_field_to_end(&mut buffer)).unwrap();
+		assert_eq!(b"msg1".to_vec(), buffer);
+		block_on(message.ignore_rest()).unwrap();
+	}
+
+	let mut buf = MessageBuf::new();
+	assert!(block_on(parser.read_message_into(&mut buf)).unwrap());
+	assert_eq!(vec![&b"msg2"[..], b"msg2field2"], buf.iter().collect::<Vec<_>>());
+	assert!(!block_on(parser.read_message_into(&mut buf)).unwrap());
+}
+
+#[test]
+fn it_can_ignore_the_rest_of_a_partially_read_field() {
+	let mut parser = AsyncPullParser::new(Trickle::new(b"msg1 {9}a b\n{1}} c\nmsg2 {3}{5}\n"));
+
+	{
+		let mut message = parser.get_message().unwrap().unwrap();
+		block_on(message.read_field_to_end(&mut Vec::new())).unwrap();
+		{
+			let mut field = message.get_field().unwrap().unwrap();
+			let mut chunk = [0u8; 2];
+			block_on(field.read_exact(&mut chunk)).unwrap();
+			assert_eq!(b"a ", &chunk);
+		}
+		block_on(message.ignore_rest()).unwrap();
+	}
+
+	let mut buf = MessageBuf::new();
+	assert!(block_on(parser.read_message_into(&mut buf)).unwrap());
+	assert_eq!(vec![&b"msg2"[..], b"{5}"], buf.iter().collect::<Vec<_>>());
+	assert!(!block_on(parser.read_message_into(&mut buf)).unwrap());
+}
+
+#[test]
+fn it_reports_errors() {
+	let mut parser = AsyncPullParser::new(Cursor::new(b"{9000000000000000000000}blahblah\n"));
+	assert!(block_on(parser.read_owned_message()).is_err());
+	assert!(parser.get_message().is_err());
+
+	let mut parser = AsyncPullParser::new(Cursor::new(b"0 ape\rkatt\n"));
+	assert!(block_on(parser.read_owned_message()).is_err());
+
+	let mut parser = AsyncPullParser::new(Trickle::new(b"0 {10}katt"));
+	assert!(block_on(parser.read_owned_message()).is_err());
+}
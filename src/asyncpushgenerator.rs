@@ -0,0 +1,486 @@
+/*!
+An asynchronous counterpart of `pushgenerator`, over `futures_io::AsyncWrite`.
+Available with the `async` feature.
+
+Since `Drop` can not wait for I/O, the generated bytes are collected in an
+internal buffer, which is written by the futures returned from the writing
+functions. A message must be finished with `Message::finish`, which writes
+the terminating newline and flushes, according to the same policy as
+`PushGenerator`. A message that is dropped without being finished is still
+terminated, but the newline is only written along with the next message or
+by `AsyncPushGenerator::flush`.
+*/
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures_io::AsyncWrite;
+
+use pushgenerator::{Error, LineEnding, should_escape, escape_header, MAX_ESCAPE_HEADER_LEN};
+
+enum PushGeneratorState {
+	Initial,
+	GeneratingMessage,
+	Error(Error),
+}
+
+pub struct AsyncPushGenerator<W> {
+	inner: W,
+	state: PushGeneratorState,
+	auto_flush: bool,
+	line_ending: LineEnding,
+	pending: Vec<u8>,
+	pending_start: usize,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncPushGenerator<W> {
+	pub fn new(inner: W) -> AsyncPushGenerator<W> {
+		AsyncPushGenerator {
+			inner: inner,
+			state: PushGeneratorState::Initial,
+			auto_flush: true,
+			line_ending: LineEnding::Lf,
+			pending: Vec::new(),
+			pending_start: 0,
+		}
+	}
+
+	pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+		self.line_ending = line_ending;
+	}
+
+	pub fn line_ending(&self) -> LineEnding {
+		self.line_ending
+	}
+
+	pub fn next_message<'x>(&'x mut self) -> Result<Message<'x, W>, Error> {
+		match self.state {
+			PushGeneratorState::Initial => {
+				self.state = PushGeneratorState::GeneratingMessage;
+				Ok(Message::new(self))
+			},
+			PushGeneratorState::GeneratingMessage => {
+				Err(Error::Unspecified("Finish message before starting a new one"))
+			},
+			PushGeneratorState::Error(ref err) => Err(err.clone())
+		}
+	}
+
+	/// Write everything generated so far and flush the underlying stream.
+	pub fn flush<'x>(&'x mut self) -> WritePending<'x, W> {
+		WritePending::new(Ok(self), true)
+	}
+
+	pub fn write_message<'x>(&'x mut self, msg: &[&[u8]]) -> WritePending<'x, W> {
+		match self.state {
+			PushGeneratorState::Initial => {},
+			PushGeneratorState::GeneratingMessage =>
+				return WritePending::new(Err(Error::Unspecified("Finish message before starting a new one")), false),
+			PushGeneratorState::Error(ref err) => return WritePending::new(Err(err.clone()), false),
+		}
+		for (i, &field) in msg.iter().enumerate() {
+			if i > 0 {
+				self.pending.push(b' ');
+			}
+			if field.len() == 0 {
+				self.pending.extend_from_slice(b"{0}");
+			} else {
+				encode_chunk(&mut self.pending, field);
+			}
+		}
+		self.pending.extend_from_slice(self.line_ending.as_bytes());
+		let auto_flush = self.auto_flush;
+		WritePending::new(Ok(self), auto_flush)
+	}
+
+	fn poll_write_pending(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
+		while self.pending_start < self.pending.len() {
+			match Pin::new(&mut self.inner).poll_write(cx, &self.pending[self.pending_start..]) {
+				Poll::Ready(Ok(0)) => return Poll::Ready(Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write whole message"))),
+				Poll::Ready(Ok(len)) => self.pending_start += len,
+				Poll::Ready(Err(ref err)) if err.kind() == io::ErrorKind::Interrupted => {},
+				Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+		self.pending.clear();
+		self.pending_start = 0;
+		Poll::Ready(Ok(()))
+	}
+
+	fn fail(&mut self, err: &'static str) -> Error {
+		let err = Error::Unspecified(err);
+		self.state = PushGeneratorState::Error(err.clone());
+		err
+	}
+}
+
+fn encode_chunk(pending: &mut Vec<u8>, buf: &[u8]) {
+	if should_escape(buf) {
+		let mut header = [0u8; MAX_ESCAPE_HEADER_LEN];
+		let header_len = escape_header(buf.len(), &mut header);
+		pending.extend_from_slice(&header[0..header_len]);
+	}
+	pending.extend_from_slice(buf);
+}
+
+/// A future that writes the generated bytes to the underlying stream, and
+/// optionally flushes it.
+pub struct WritePending<'a, W: 'a> {
+	generator: Option<Result<&'a mut AsyncPushGenerator<W>, Error>>,
+	flush: bool,
+}
+
+impl<'a, W: AsyncWrite + Unpin> WritePending<'a, W> {
+	fn new(generator: Result<&'a mut AsyncPushGenerator<W>, Error>, flush: bool) -> WritePending<'a, W> {
+		WritePending {
+			generator: Some(generator),
+			flush: flush,
+		}
+	}
+}
+
+impl<'a, W: AsyncWrite + Unpin> Future for WritePending<'a, W> {
+	type Output = Result<(), Error>;
+
+	fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), Error>> {
+		let this = &mut *self;
+		let generator = match this.generator.take() {
+			Some(Ok(generator)) => generator,
+			Some(Err(err)) => return Poll::Ready(Err(err)),
+			None => panic!("WritePending polled after completion"),
+		};
+		if let PushGeneratorState::Error(ref err) = generator.state {
+			return Poll::Ready(Err(err.clone()));
+		}
+
+		match generator.poll_write_pending(cx) {
+			Poll::Ready(Ok(())) => {},
+			Poll::Ready(Err(_err)) => return Poll::Ready(Err(generator.fail("Nested error"))),
+			Poll::Pending => {
+				this.generator = Some(Ok(generator));
+				return Poll::Pending;
+			},
+		}
+
+		if this.flush {
+			match Pin::new(&mut generator.inner).poll_flush(cx) {
+				Poll::Ready(Ok(())) => {},
+				Poll::Ready(Err(_err)) => return Poll::Ready(Err(generator.fail("Autoflush failed"))),
+				Poll::Pending => {
+					this.generator = Some(Ok(generator));
+					return Poll::Pending;
+				},
+			}
+		}
+
+		Poll::Ready(Ok(()))
+	}
+}
+
+enum MessageState {
+	BeforeFirstField,
+	AfterFirstField,
+	GeneratingField,
+}
+
+pub struct Message<'a, W: 'a> {
+	inner: Option<&'a mut AsyncPushGenerator<W>>,
+	state: MessageState,
+}
+
+impl<'a, W: AsyncWrite + Unpin> Message<'a, W> {
+	fn new(inner: &'a mut AsyncPushGenerator<W>) -> Message<'a, W> {
+		Message {
+			inner: Some(inner),
+			state: MessageState::BeforeFirstField,
+		}
+	}
+
+	fn generator(&mut self) -> &mut AsyncPushGenerator<W> {
+		self.inner.as_mut().expect("The message is not finished")
+	}
+
+	pub fn next_field<'x>(&'x mut self) -> Result<Field<'x, 'a, W>, Error> {
+		match self.state {
+			MessageState::BeforeFirstField => {
+				self.state = MessageState::GeneratingField;
+				Ok(Field::new(self))
+			},
+			MessageState::AfterFirstField => {
+				self.generator().pending.push(b' ');
+				self.state = MessageState::GeneratingField;
+				Ok(Field::new(self))
+			},
+			MessageState::GeneratingField =>
+				Err(Error::Unspecified("You must close the previous field before starting a new one"))
+		}
+	}
+
+	pub fn write_field<'x>(&'x mut self, buf: &[u8]) -> WritePending<'x, W> {
+		match self.next_field() {
+			Ok(mut field) => {
+				field.append(buf);
+			},
+			Err(err) => return WritePending::new(Err(err), false),
+		}
+		WritePending::new(Ok(self.generator()), false)
+	}
+
+	/// Terminate the message, write it and flush the underlying stream if
+	/// the generator is set to flush automatically.
+	pub fn finish(mut self) -> WritePending<'a, W> {
+		let generator = self.inner.take().expect("The message is not finished");
+		end_message(generator);
+		let auto_flush = generator.auto_flush;
+		WritePending::new(Ok(generator), auto_flush)
+	}
+}
+
+fn end_message<W>(generator: &mut AsyncPushGenerator<W>) {
+	let line_ending = generator.line_ending.as_bytes();
+	generator.pending.extend_from_slice(line_ending);
+	if let PushGeneratorState::GeneratingMessage = generator.state {
+		generator.state = PushGeneratorState::Initial;
+	}
+}
+
+impl<'a, W> Drop for Message<'a, W> {
+	fn drop(&mut self) {
+		if let Some(generator) = self.inner.take() {
+			end_message(generator);
+		}
+	}
+}
+
+pub struct Field<'a, 'b: 'a, W: 'b + AsyncWrite + Unpin> {
+	inner: &'a mut Message<'b, W>,
+	empty: bool,
+}
+
+impl<'a, 'b, W: AsyncWrite + Unpin> Field<'a, 'b, W> {
+	fn new(inner: &'a mut Message<'b, W>) -> Field<'a, 'b, W> {
+		Field {
+			inner: inner,
+			empty: true,
+		}
+	}
+
+	fn append(&mut self, buf: &[u8]) {
+		encode_chunk(&mut self.inner.generator().pending, buf);
+		self.empty = self.empty && (buf.len() == 0);
+	}
+
+	/// Append `buf` to the field, escaping it as necessary, and write it.
+	pub fn write_all<'x>(&'x mut self, buf: &[u8]) -> WritePending<'x, W> {
+		self.append(buf);
+		WritePending::new(Ok(self.inner.generator()), false)
+	}
+}
+
+impl<'a, 'b, W: AsyncWrite + Unpin> Drop for Field<'a, 'b, W> {
+	fn drop(&mut self) {
+		if self.empty {
+			self.inner.generator().pending.extend_from_slice(b"{0}");
+		}
+		self.inner.state = MessageState::AfterFirstField;
+	}
+}
+
+/// Each write is buffered, and written to the underlying stream by the next
+/// write or flush, so at most one chunk is held in memory.
+impl<'a, 'b, W: AsyncWrite + Unpin> AsyncWrite for Field<'a, 'b, W> {
+	fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+		let generator = self.inner.generator();
+		match generator.poll_write_pending(cx) {
+			Poll::Ready(Ok(())) => {},
+			Poll::Ready(Err(err)) => {
+				generator.fail("Nested error");
+				return Poll::Ready(Err(err));
+			},
+			Poll::Pending => return Poll::Pending,
+		}
+		self.append(buf);
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		let generator = self.inner.generator();
+		let result = match generator.poll_write_pending(cx) {
+			Poll::Ready(Ok(())) => Pin::new(&mut generator.inner).poll_flush(cx),
+			other => other,
+		};
+		if let Poll::Ready(Err(_)) = result {
+			generator.fail("Nested error");
+		}
+		result
+	}
+
+	fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+		// Closing a field does not close the stream
+		self.poll_flush(cx)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::io;
+	use std::pin::Pin;
+	use std::task::{Context, Poll};
+	use futures::executor::block_on;
+	use futures::io::{AsyncWrite, AsyncWriteExt};
+	use asyncpushgenerator::*;
+
+	// Accepts one byte per write, and is pending every other time it is polled
+	struct Trickle {
+		data: Vec<u8>,
+		pending: bool,
+		flushes: usize,
+	}
+
+	impl Trickle {
+		fn new() -> Trickle {
+			Trickle { data: Vec::new(), pending: true, flushes: 0 }
+		}
+
+		fn poll_pending(&mut self, cx: &mut Context) -> bool {
+			self.pending = !self.pending;
+			if self.pending {
+				cx.waker().wake_by_ref();
+			}
+			self.pending
+		}
+	}
+
+	impl AsyncWrite for Trickle {
+		fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+			if self.poll_pending(cx) {
+				return Poll::Pending;
+			}
+			self.data.push(buf[0]);
+			Poll::Ready(Ok(1))
+		}
+
+		fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+			if self.poll_pending(cx) {
+				return Poll::Pending;
+			}
+			self.flushes += 1;
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_close(self: Pin<&mut Self>, cx: &mut Context) -> Poll<io::Result<()>> {
+			self.poll_flush(cx)
+		}
+	}
+
+	#[test]
+	fn it_works() {
+		let mut generator = AsyncPushGenerator::new(Trickle::new());
+
+		{
+			let mut message = generator.next_message().unwrap();
+
+			{
+				let mut field = message.next_field().unwrap();
+				block_on(field.write_all(b"0")).unwrap();
+			}
+
+			{
+				let mut field = message.next_field().unwrap();
+				block_on(field.write_all(b"katter")).unwrap();
+				block_on(field.write_all(b" ape")).unwrap();
+			}
+
+			block_on(message.write_field(b"")).unwrap();
+			block_on(message.finish()).unwrap();
+		}
+
+		assert_eq!(b"0 katter{4} ape {0}\n".to_vec(), generator.inner.data);
+		assert_eq!(1, generator.inner.flushes);
+	}
+
+	#[test]
+	fn it_has_convenience_functions() {
+		let mut generator = AsyncPushGenerator::new(Vec::new());
+		generator.set_line_ending(LineEnding::CrLf);
+
+		block_on(generator.write_message(&[b"0", b"error", b"katter ape"])).unwrap();
+		block_on(generator.write_message(&[b"", b""])).unwrap();
+
+		assert_eq!(b"0 error {10}katter ape\r\n{0} {0}\r\n".to_vec(), generator.inner);
+	}
+
+	#[test]
+	fn fields_are_async_writers() {
+		let mut generator = AsyncPushGenerator::new(Trickle::new());
+
+		{
+			let mut message = generator.next_message().unwrap();
+			{
+				let mut field = message.next_field().unwrap();
+				block_on(futures::io::copy(&b"lol cats"[..], &mut field)).unwrap();
+			}
+			block_on(message.finish()).unwrap();
+		}
+
+		assert_eq!(b"{8}lol cats\n".to_vec(), generator.inner.data);
+	}
+
+	#[test]
+	fn dropped_messages_are_terminated_by_the_next_write() {
+		let mut generator = AsyncPushGenerator::new(Vec::new());
+
+		{
+			let mut message = generator.next_message().unwrap();
+			block_on(message.write_field(b"0")).unwrap();
+		}
+		assert_eq!(b"0".to_vec(), generator.inner);
+
+		block_on(generator.write_message(&[b"1"])).unwrap();
+		assert_eq!(b"0\n1\n".to_vec(), generator.inner);
+	}
+
+	struct Broken;
+
+	impl AsyncWrite for Broken {
+		fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, _buf: &[u8]) -> Poll<io::Result<usize>> {
+			Poll::Ready(Err(io::Error::new(io::ErrorKind::Other, "broken")))
+		}
+
+		fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+
+		fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<io::Result<()>> {
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	#[test]
+	fn errors_put_the_generator_in_a_failed_state() {
+		let mut generator = AsyncPushGenerator::new(Broken);
+		assert!(block_on(generator.write_message(&[b"0"])).is_err());
+		assert!(generator.next_message().is_err());
+	}
+
+	#[test]
+	fn field_write_errors_put_the_generator_in_a_failed_state() {
+		let mut generator = AsyncPushGenerator::new(Broken);
+
+		{
+			let mut message = generator.next_message().unwrap();
+			{
+				let mut field = message.next_field().unwrap();
+				// The first chunk is only buffered, and written by the next
+				assert_eq!(1, block_on(AsyncWriteExt::write(&mut field, b"a")).unwrap());
+				assert!(block_on(AsyncWriteExt::write(&mut field, b"b")).is_err());
+			}
+			assert!(block_on(message.finish()).is_err());
+		}
+		assert!(generator.next_message().is_err());
+		assert!(block_on(generator.flush()).is_err());
+	}
+}
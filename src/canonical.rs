@@ -0,0 +1,96 @@
+/*!
+Canonical encoding of PlainTalk messages.
+
+PlainTalk allows the same logical message to be encoded in many ways, since
+escape sequences can be placed anywhere in a field. When messages are signed
+or hashed, all parties must agree on a single encoding. The canonical encoding
+is defined as follows:
+
+ * A field is written verbatim if it is between 1 and 100 bytes long and
+   contains none of `{`, SP, CR or LF.
+ * Any other non-empty field is written as a single escape sequence covering
+   the entire field: `{`, the length in decimal without leading zeros, `}`,
+   followed by the field contents.
+ * An empty field is written as `{0}`.
+ * Fields are separated by a single SP, and each message is terminated by the
+   line ending of the generator, LF by default.
+ * A message with no fields, or a single empty field, is indistinguishable
+   from an empty line, and is omitted altogether.
+
+`PushGenerator::set_canonical` makes a generator produce this encoding, and
+`canonicalize` converts an arbitrary PlainTalk stream to it.
+*/
+
+use std::io::{self, Read, Write};
+
+use pullparser::{PullParser, Error};
+use pushgenerator::{self, PushGenerator};
+
+fn from_generator_error(err: pushgenerator::Error) -> Error {
+	match err {
+		pushgenerator::Error::Unspecified(err) => Error::Unspecified(err),
+	}
+}
+
+/// Parse all messages from `input` and write them in canonical form to
+/// `output`. Each field is buffered in memory while it is being converted.
+pub fn canonicalize<R: Read, W: Write>(input: R, output: W) -> Result<(), Error> {
+	let mut parser = PullParser::new(input);
+	let mut generator = PushGenerator::new(output);
+	generator.set_canonical(true);
+
+	while let Some(mut message) = try!{parser.get_message()} {
+		let mut canonical_message = try!{generator.next_message().map_err(from_generator_error)};
+		while let Some(mut field) = try!{message.get_field()} {
+			let mut canonical_field = try!{canonical_message.next_field().map_err(from_generator_error)};
+			try!{io::copy(&mut field, &mut canonical_field)};
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::Cursor;
+	use canonical::*;
+
+	fn canonical(data: &[u8]) -> Vec<u8> {
+		let mut buffer = Vec::new();
+		canonicalize(Cursor::new(data), &mut buffer).unwrap();
+		buffer
+	}
+
+	#[test]
+	fn it_normalizes_escape_sequences() {
+		assert_eq!(
+			b"0 {10}katter ape lolcats\n".to_vec(),
+			canonical(b"{1}0 katter{1} ape l{00}ol{4}cats\r\n")
+		);
+	}
+
+	#[test]
+	fn it_escapes_empty_and_long_fields() {
+		let long_field = vec![b'x'; 101];
+		let mut data = b"{} ".to_vec();
+		data.extend(&long_field);
+		data.extend(b"\n");
+
+		let mut expected = b"{0} {101}".to_vec();
+		expected.extend(&long_field);
+		expected.extend(b"\n");
+
+		assert_eq!(expected, canonical(&data));
+	}
+
+	#[test]
+	fn it_drops_empty_lines() {
+		assert_eq!(b"a\nb\n".to_vec(), canonical(b"\n{0}\na\n\r\nb\n"));
+	}
+
+	#[test]
+	fn it_is_idempotent() {
+		let once = canonical(b"0 {3}a b {0}{2}\r\n 1\n");
+		assert_eq!(once, canonical(&once));
+	}
+}
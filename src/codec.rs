@@ -0,0 +1,380 @@
+/*!
+PlainTalk framing for `tokio_util::codec`, available with the `codec`
+feature.
+
+`PlainTalkCodec` decodes to `OwnedMessage` with the same rules as
+`pullparser`, and encodes with the same rules as `pushgenerator`.
+*/
+
+use bytes::{Buf, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use encoder::encode_message;
+use message::OwnedMessage;
+use pullparser::Error;
+
+const CURLY_L: u8 = b'{';
+const CURLY_R: u8 = b'}';
+const SP: u8 = b' ';
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+const NUM_0: u8 = b'0';
+const NUM_9: u8 = b'9';
+
+#[derive(Debug, Clone, Copy)]
+enum DecoderState {
+	Field,
+	EscapeHeader(usize),
+	EscapedBytes(usize),
+	LineFeed,
+	Error(&'static str),
+}
+
+#[derive(Debug)]
+pub struct PlainTalkCodec {
+	max_message_length: Option<usize>,
+	state: DecoderState,
+	message: OwnedMessage,
+	field: Vec<u8>,
+	message_length: usize,
+	empty: bool,
+}
+
+impl PlainTalkCodec {
+	pub fn new() -> PlainTalkCodec {
+		PlainTalkCodec {
+			max_message_length: None,
+			state: DecoderState::Field,
+			message: OwnedMessage::new(),
+			field: Vec::new(),
+			message_length: 0,
+			empty: true,
+		}
+	}
+
+	/// Fail decoding when a message takes more than `max_message_length`
+	/// bytes of input, instead of buffering it without bounds. All the bytes
+	/// count, including separators, escape headers and the line ending.
+	pub fn set_max_message_length(&mut self, max_message_length: Option<usize>) {
+		self.max_message_length = max_message_length;
+	}
+
+	pub fn max_message_length(&self) -> Option<usize> {
+		self.max_message_length
+	}
+
+	fn check_length(&self, additional: usize) -> Result<(), Error> {
+		match self.max_message_length {
+			Some(max) if additional > max - self.message_length =>
+				Err(Error::Unspecified("Message too long")),
+			_ => Ok(()),
+		}
+	}
+
+	// Consume `len` bytes of input, counting them against the limit
+	fn advance(&mut self, src: &mut BytesMut, len: usize) -> Result<(), Error> {
+		try!{self.check_length(len)};
+		self.message_length += len;
+		src.advance(len);
+		Ok(())
+	}
+
+	fn push_data(&mut self, data: &[u8]) {
+		self.field.extend_from_slice(data);
+		self.empty = self.empty && data.len() == 0;
+	}
+
+	fn end_field(&mut self) {
+		self.message.push_field(&self.field);
+		self.field.clear();
+	}
+
+	fn end_line(&mut self) -> Option<OwnedMessage> {
+		self.state = DecoderState::Field;
+		self.message_length = 0;
+		if self.empty {
+			// An empty line is not a message
+			return None;
+		}
+		self.end_field();
+		self.empty = true;
+		Some(::std::mem::replace(&mut self.message, OwnedMessage::new()))
+	}
+
+	// Remember a decoding error, so that every later call fails the same
+	// way instead of continuing from a half-advanced state
+	fn check_result(&mut self, result: Result<Option<OwnedMessage>, Error>) -> Result<Option<OwnedMessage>, Error> {
+		if let Err(Error::Unspecified(err)) = result {
+			self.state = DecoderState::Error(err);
+		}
+		result
+	}
+
+	fn decode_message(&mut self, src: &mut BytesMut) -> Result<Option<OwnedMessage>, Error> {
+		if let DecoderState::Error(err) = self.state {
+			return Err(Error::Unspecified(err));
+		}
+		while src.len() > 0 {
+			match self.state {
+				DecoderState::Field => {
+					let special = src.iter().position(|&x| x == CURLY_L || x == SP || x == CR || x == LF);
+					let len = special.unwrap_or(src.len());
+					try!{self.check_length(len)};
+					self.push_data(&src[0..len]);
+					try!{self.advance(src, len)};
+
+					if special.is_some() {
+						let byte = src[0];
+						try!{self.advance(src, 1)};
+						match byte {
+							CURLY_L => self.state = DecoderState::EscapeHeader(0),
+							SP => {
+								self.end_field();
+								self.empty = false;
+							},
+							CR => self.state = DecoderState::LineFeed,
+							LF => if let Some(message) = self.end_line() {
+								return Ok(Some(message));
+							},
+							_ => unreachable!(),
+						}
+					}
+				},
+				DecoderState::EscapeHeader(escaped_bytes) => {
+					let byte = src[0];
+					try!{self.advance(src, 1)};
+					match byte {
+						CURLY_R => {
+							try!{self.check_length(escaped_bytes)};
+							self.state = match escaped_bytes {
+								0 => DecoderState::Field,
+								n => DecoderState::EscapedBytes(n),
+							};
+						},
+						x if NUM_0 <= x && x <= NUM_9 => {
+							match escaped_bytes.checked_mul(10).and_then(|y| y.checked_add((x - NUM_0) as usize)) {
+								Some(y) => self.state = DecoderState::EscapeHeader(y),
+								None => return Err(Error::Unspecified("Overflow in PlainTalk escape sequence")),
+							}
+						},
+						_ => return Err(Error::Unspecified("Invalid symbol in PlainTalk escape sequence")),
+					}
+				},
+				DecoderState::EscapedBytes(size_left) => {
+					let len = ::std::cmp::min(size_left, src.len());
+					try!{self.check_length(len)};
+					self.push_data(&src[0..len]);
+					try!{self.advance(src, len)};
+					self.state = match size_left - len {
+						0 => DecoderState::Field,
+						x => DecoderState::EscapedBytes(x),
+					};
+				},
+				DecoderState::Error(_) => unreachable!(),
+				DecoderState::LineFeed => {
+					let byte = src[0];
+					try!{self.advance(src, 1)};
+					if byte != LF {
+						return Err(Error::Unspecified("Invalid byte after CR"));
+					}
+					if let Some(message) = self.end_line() {
+						return Ok(Some(message));
+					}
+				},
+			}
+		}
+		Ok(None)
+	}
+}
+
+impl Default for PlainTalkCodec {
+	fn default() -> PlainTalkCodec {
+		PlainTalkCodec::new()
+	}
+}
+
+impl Decoder for PlainTalkCodec {
+	type Item = OwnedMessage;
+	type Error = Error;
+
+	fn decode(&mut self, src: &mut BytesMut) -> Result<Option<OwnedMessage>, Error> {
+		let result = self.decode_message(src);
+		self.check_result(result)
+	}
+
+	fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<OwnedMessage>, Error> {
+		let result = match try!{self.decode(src)} {
+			Some(message) => Ok(Some(message)),
+			None => match self.state {
+				DecoderState::Field if self.empty => Ok(None),
+				_ => Err(Error::Unspecified("Unexpected EOF")),
+			},
+		};
+		self.check_result(result)
+	}
+}
+
+impl<'a> Encoder<&'a OwnedMessage> for PlainTalkCodec {
+	type Error = Error;
+
+	fn encode(&mut self, message: &'a OwnedMessage, dst: &mut BytesMut) -> Result<(), Error> {
+		dst.extend_from_slice(&message.encode());
+		Ok(())
+	}
+}
+
+impl Encoder<OwnedMessage> for PlainTalkCodec {
+	type Error = Error;
+
+	fn encode(&mut self, message: OwnedMessage, dst: &mut BytesMut) -> Result<(), Error> {
+		self.encode(&message, dst)
+	}
+}
+
+impl<'a, 'b> Encoder<&'a [&'b [u8]]> for PlainTalkCodec {
+	type Error = Error;
+
+	fn encode(&mut self, message: &'a [&'b [u8]], dst: &mut BytesMut) -> Result<(), Error> {
+		dst.extend_from_slice(&encode_message(message));
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use bytes::BytesMut;
+	use futures::{SinkExt, StreamExt};
+	use tokio::io;
+	use tokio::runtime;
+	use tokio_util::codec::{Decoder, Encoder, FramedRead, FramedWrite};
+	use codec::*;
+
+	fn decode_all(codec: &mut PlainTalkCodec, data: &[u8]) -> Vec<OwnedMessage> {
+		let mut buf = BytesMut::from(data);
+		let mut messages = Vec::new();
+		while let Some(message) = codec.decode_eof(&mut buf).unwrap() {
+			messages.push(message);
+		}
+		messages
+	}
+
+	#[test]
+	fn it_decodes_like_the_pull_parser() {
+		let data = b"{6}0{1} a{10}pe katt\nlol fie{3}ld 2\r\n\n{}\n{0} {0}\n2 lol\n";
+		let expected: Vec<OwnedMessage> = vec![
+			"{6}0{1} a{10}pe katt\nlol fie{3}ld 2".parse().unwrap(),
+			"{0} {0}".parse().unwrap(),
+			"2 lol".parse().unwrap(),
+		];
+
+		assert_eq!(expected, decode_all(&mut PlainTalkCodec::new(), data));
+
+		// Feeding one byte at a time gives the same result
+		let mut codec = PlainTalkCodec::new();
+		let mut buf = BytesMut::new();
+		let mut messages = Vec::new();
+		for &byte in data.iter() {
+			buf.extend_from_slice(&[byte]);
+			if let Some(message) = codec.decode(&mut buf).unwrap() {
+				messages.push(message);
+			}
+		}
+		assert_eq!(expected, messages);
+	}
+
+	#[test]
+	fn it_rejects_invalid_input() {
+		let mut buf = BytesMut::from(&b"{9000000000000000000000}blahblah\n"[..]);
+		assert!(PlainTalkCodec::new().decode(&mut buf).is_err());
+
+		let mut buf = BytesMut::from(&b"0 ape\rkatt\n"[..]);
+		assert!(PlainTalkCodec::new().decode(&mut buf).is_err());
+
+		let mut buf = BytesMut::from(&b"0 {10}ape"[..]);
+		assert!(PlainTalkCodec::new().decode_eof(&mut buf).is_err());
+	}
+
+	#[test]
+	fn it_keeps_failing_after_an_error() {
+		let mut codec = PlainTalkCodec::new();
+		let mut buf = BytesMut::from(&b"0 {x}\n"[..]);
+		assert!(codec.decode(&mut buf).is_err());
+
+		let mut buf = BytesMut::from(&b"1 ok\n"[..]);
+		assert!(codec.decode(&mut buf).is_err());
+		assert!(codec.decode_eof(&mut buf).is_err());
+		assert!(codec.decode(&mut BytesMut::new()).is_err());
+	}
+
+	fn limited(max_message_length: usize) -> PlainTalkCodec {
+		let mut codec = PlainTalkCodec::new();
+		codec.set_max_message_length(Some(max_message_length));
+		codec
+	}
+
+	#[test]
+	fn it_limits_message_length() {
+		let mut codec = limited(10);
+
+		// Empty lines are not part of any message
+		assert_eq!(2, decode_all(&mut codec, b"0 1234567\n\n\n\n\n\n\n\n\n\n\n0 1234567\n").len());
+
+		let mut buf = BytesMut::from(&b"0 12345678\n"[..]);
+		assert!(codec.decode(&mut buf).is_err());
+
+		let mut buf = BytesMut::from(&b"{8}"[..]);
+		assert!(limited(10).decode(&mut buf).is_err());
+	}
+
+	#[test]
+	fn it_limits_the_length_of_separators_and_escape_headers() {
+		for data in &[&b" "[..], b"{0}", b"{}", b"{0000}"] {
+			let mut codec = limited(100);
+			let mut buf = BytesMut::new();
+			let mut result = Ok(None);
+			for _ in 0..1000 {
+				buf.extend_from_slice(data);
+				result = codec.decode(&mut buf);
+				if result.is_err() {
+					break;
+				}
+			}
+			assert!(result.is_err(), "{:?}", data);
+		}
+	}
+
+	#[test]
+	fn it_encodes_like_the_generator() {
+		let mut buf = BytesMut::new();
+		let mut codec = PlainTalkCodec::new();
+		codec.encode(&[&b"0"[..], b"katter ape", b""][..], &mut buf).unwrap();
+		codec.encode("1 ok".parse::<OwnedMessage>().unwrap(), &mut buf).unwrap();
+		assert_eq!(&b"0 {10}katter ape {0}\n1 ok\n"[..], &buf[..]);
+	}
+
+	#[test]
+	fn it_works_over_a_duplex_stream() {
+		let rt = runtime::Builder::new_current_thread().build().unwrap();
+		let (client, server) = io::duplex(16);
+		let mut writer = FramedWrite::new(client, PlainTalkCodec::new());
+		let mut reader = FramedRead::new(server, PlainTalkCodec::new());
+
+		let long_field = vec![b'x'; 1000];
+		let messages: Vec<OwnedMessage> = vec![
+			"0 protocol lol".parse().unwrap(),
+			vec![b"1".to_vec(), long_field, b"a b\r\n".to_vec()].into(),
+		];
+
+		let mut stream = futures::stream::iter(messages.clone().into_iter().map(Ok));
+		let send = writer.send_all(&mut stream);
+		let receive = reader.by_ref().take(2).collect::<Vec<_>>();
+		let (sent, received) = rt.block_on(futures::future::join(send, receive));
+		sent.unwrap();
+
+		let received: Vec<OwnedMessage> = received.into_iter().map(|message| message.unwrap()).collect();
+		assert_eq!(messages, received);
+
+		drop(writer);
+		assert!(rt.block_on(reader.next()).is_none());
+	}
+}
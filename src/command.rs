@@ -0,0 +1,163 @@
+/*!
+Commands of the form `verb args…`, as in the `protocol lol` of a `0 protocol
+lol` message, mapped to and from Rust values.
+
+`ToMessage` and `FromMessage` write and read the verb and its arguments as
+fields of a message. Any fields before the verb, such as a message id, are
+handled by the caller. The traits are usually derived for enums with the
+`plaintalk-derive` crate, where each variant is a verb and the fields of the
+variant are its arguments:
+
+```ignore
+#[derive(FromMessage, ToMessage)]
+enum Command {
+    Protocol(String),
+    #[plaintalk(verb = "get")]
+    Fetch { key: String, default: Option<String> },
+    Delete(#[plaintalk(varargs)] Vec<String>),
+}
+```
+
+The verb is the name of the variant in snake case unless it is given with
+`#[plaintalk(verb = "...")]`. Arguments are encoded as described in the
+`field` module. Arguments of type `Option` may be left out at the end of a
+message, and a last argument marked with `#[plaintalk(varargs)]` collects
+all the remaining fields.
+
+The functions in this module are the building blocks for the derived
+implementations.
+*/
+
+use std::io::Write;
+use std::iter::FromIterator;
+
+use field::FromField;
+use pullparser::{self, Error};
+use pushgenerator;
+
+pub trait ToMessage {
+	/// Write the verb and the arguments as the next fields of `message`.
+	fn write_fields<W: Write>(&self, message: &mut pushgenerator::Message<W>) -> Result<(), pushgenerator::Error>;
+}
+
+pub trait FromMessage: Sized {
+	/// Read the verb and the arguments from the remaining fields of
+	/// `message`, which must all be consumed.
+	fn read_fields(message: &mut pullparser::Message) -> Result<Self, Error>;
+}
+
+pub fn read_verb(message: &mut pullparser::Message) -> Result<Vec<u8>, Error> {
+	let mut verb = Vec::new();
+	match try!{message.read_field_to_end(&mut verb)} {
+		Some(_) => Ok(verb),
+		None => Err(Error::Unspecified("Missing verb")),
+	}
+}
+
+pub fn read_argument<T: FromField>(message: &mut pullparser::Message) -> Result<T, Error> {
+	match try!{message.read()} {
+		Some(value) => Ok(value),
+		None => Err(Error::Unspecified("Missing argument")),
+	}
+}
+
+/// Read an argument that is `None` when it is empty or left out.
+pub fn read_optional<T: FromField>(message: &mut pullparser::Message) -> Result<Option<T>, Error> {
+	Ok(try!{message.read::<Option<T>>()}.and_then(|value| value))
+}
+
+pub fn read_varargs<T: FromField, C: FromIterator<T>>(message: &mut pullparser::Message) -> Result<C, Error> {
+	let mut values = Vec::new();
+	while let Some(value) = try!{message.read()} {
+		values.push(value);
+	}
+	Ok(values.into_iter().collect())
+}
+
+/// Fail if there are fields left in `message`.
+pub fn expect_end(message: &pullparser::Message) -> Result<(), Error> {
+	if message.at_end() {
+		Ok(())
+	} else {
+		Err(Error::Unspecified("Too many arguments"))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::Cursor;
+	use command::*;
+	use pullparser::PullParser;
+	use pushgenerator::PushGenerator;
+
+	#[derive(Debug, PartialEq)]
+	enum Command {
+		Get(String, Option<u32>),
+		Delete(Vec<String>),
+	}
+
+	impl ToMessage for Command {
+		fn write_fields<W: Write>(&self, message: &mut pushgenerator::Message<W>) -> Result<(), pushgenerator::Error> {
+			match *self {
+				Command::Get(ref key, ref limit) => {
+					try!{message.write_field(b"get")};
+					try!{message.write(key)};
+					message.write(limit)
+				},
+				Command::Delete(ref keys) => {
+					try!{message.write_field(b"delete")};
+					for key in keys {
+						try!{message.write(key)};
+					}
+					Ok(())
+				},
+			}
+		}
+	}
+
+	impl FromMessage for Command {
+		fn read_fields(message: &mut pullparser::Message) -> Result<Command, Error> {
+			let command = match &try!{read_verb(message)}[..] {
+				b"get" => Command::Get(try!{read_argument(message)}, try!{read_optional(message)}),
+				b"delete" => Command::Delete(try!{read_varargs(message)}),
+				_ => return Err(Error::Unspecified("Unknown verb")),
+			};
+			try!{expect_end(message)};
+			Ok(command)
+		}
+	}
+
+	fn parse(data: &[u8]) -> Result<Command, Error> {
+		let mut parser = PullParser::new(Cursor::new(data));
+		let mut message = try!{parser.get_message()}.unwrap();
+		Command::read_fields(&mut message)
+	}
+
+	#[test]
+	fn it_reads_arguments() {
+		assert_eq!(Command::Get("a".into(), Some(3)), parse(b"get a 3\n").unwrap());
+		assert_eq!(Command::Get("a".into(), None), parse(b"get a {0}\n").unwrap());
+		assert_eq!(Command::Get("a".into(), None), parse(b"get a\n").unwrap());
+		assert_eq!(Command::Delete(vec![]), parse(b"delete\n").unwrap());
+		assert_eq!(Command::Delete(vec!["a".into(), "b".into()]), parse(b"delete a b\n").unwrap());
+
+		assert!(parse(b"get\n").is_err());
+		assert!(parse(b"get a 3 4\n").is_err());
+		assert!(parse(b"get a x\n").is_err());
+		assert!(parse(b"put a\n").is_err());
+	}
+
+	#[test]
+	fn it_writes_arguments() {
+		let mut buffer = Vec::new();
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			for command in &[Command::Get("a b".into(), None), Command::Delete(vec!["c".into()])] {
+				let mut message = generator.next_message().unwrap();
+				message.write_field(b"0").unwrap();
+				command.write_fields(&mut message).unwrap();
+			}
+		}
+		assert_eq!(&b"0 get {3}a b {0}\n0 delete c\n"[..], &buffer[..]);
+	}
+}
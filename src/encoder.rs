@@ -0,0 +1,115 @@
+/*!
+Encoding of complete messages to bytes, for when a `Write` stream is not
+available or the encoded size is needed up front. The output is identical to
+what `PushGenerator::write_message` generates.
+*/
+
+use pushgenerator::{should_escape, escape_header, MAX_ESCAPE_HEADER_LEN};
+
+/// Encode a message, including the terminating newline.
+pub fn encode_message(msg: &[&[u8]]) -> Vec<u8> {
+	let mut buf = Vec::with_capacity(encoded_len(msg));
+	encode_message_into(&mut buf, msg);
+	buf
+}
+
+/// Encode a message, including the terminating newline, appending it to
+/// `buf`.
+pub fn encode_message_into(buf: &mut Vec<u8>, msg: &[&[u8]]) {
+	encode_fields_into(buf, msg.iter().cloned())
+}
+
+pub(crate) fn encode_fields_into<'a, I: IntoIterator<Item = &'a [u8]>>(buf: &mut Vec<u8>, fields: I) {
+	let mut header = [0u8; MAX_ESCAPE_HEADER_LEN];
+	for (i, field) in fields.into_iter().enumerate() {
+		if i > 0 {
+			buf.push(b' ');
+		}
+		if field.len() == 0 {
+			buf.extend_from_slice(b"{0}");
+		} else {
+			if should_escape(field) {
+				let header_len = escape_header(field.len(), &mut header);
+				buf.extend_from_slice(&header[0..header_len]);
+			}
+			buf.extend_from_slice(field);
+		}
+	}
+	buf.push(b'\n');
+}
+
+/// The number of bytes `encode_message` would generate for the given message.
+pub fn encoded_len(msg: &[&[u8]]) -> usize {
+	encoded_fields_len(msg.iter().cloned())
+}
+
+pub(crate) fn encoded_fields_len<'a, I: IntoIterator<Item = &'a [u8]>>(fields: I) -> usize {
+	let mut len = 0;
+	for (i, field) in fields.into_iter().enumerate() {
+		if i > 0 {
+			len += 1;
+		}
+		len += encoded_field_len(field);
+	}
+	len + 1
+}
+
+fn encoded_field_len(field: &[u8]) -> usize {
+	if field.len() == 0 {
+		"{0}".len()
+	} else if should_escape(field) {
+		decimal_digits(field.len()) + 2 + field.len()
+	} else {
+		field.len()
+	}
+}
+
+fn decimal_digits(mut n: usize) -> usize {
+	let mut digits = 1;
+	while n >= 10 {
+		n /= 10;
+		digits += 1;
+	}
+	digits
+}
+
+#[cfg(test)]
+mod test {
+	use encoder::*;
+	use pushgenerator::PushGenerator;
+
+	fn generate(msg: &[&[u8]]) -> Vec<u8> {
+		let mut buffer = Vec::new();
+		PushGenerator::new(&mut buffer).write_message(msg).unwrap();
+		buffer
+	}
+
+	#[test]
+	fn it_works() {
+		assert_eq!(b"0 error success\n".to_vec(), encode_message(&[b"0", b"error", b"success"]));
+	}
+
+	#[test]
+	fn it_encodes_like_the_generator() {
+		let long_field = vec![b'x'; 1234];
+		let messages: Vec<Vec<&[u8]>> = vec![
+			vec![],
+			vec![b""],
+			vec![b"", b"katter ape", b"{", b"\r\n"],
+			vec![b"0", &long_field, b"lol"],
+		];
+
+		for msg in &messages {
+			assert_eq!(generate(msg), encode_message(msg));
+			assert_eq!(generate(msg).len(), encoded_len(msg));
+		}
+	}
+
+	#[test]
+	fn it_can_append_to_a_buffer() {
+		let mut buf = Vec::new();
+		encode_message_into(&mut buf, &[b"0", b"ok"]);
+		encode_message_into(&mut buf, &[b"1", b"a b"]);
+		assert_eq!(b"0 ok\n1 {3}a b\n".to_vec(), buf);
+	}
+}
@@ -0,0 +1,220 @@
+/*!
+Conversions between field contents and Rust values, giving a single agreed
+textual encoding for scalar values:
+
+ * Integers and floating point numbers are written in decimal, as by
+   `Display`, and parsed as by `FromStr`.
+ * `bool` is written as `true` or `false`.
+ * `char`, `str` and `String` are written as UTF-8. Parsing fails for
+   invalid UTF-8, and for `char` unless the field is exactly one character.
+ * `[u8]` and `Vec<u8>` are written verbatim.
+ * `Option<T>` is written as an empty field for `None`, and as `T` for
+   `Some`. An empty field is parsed as `None`, so `Some` of a value that is
+   written as an empty field does not survive the round trip.
+
+Use `pushgenerator::Message::write` and `pullparser::Message::read` to
+generate and parse fields with these conversions.
+*/
+
+use std::io::{self, Write};
+use std::str::{self, FromStr};
+
+use pullparser::Error;
+
+pub trait ToField {
+	/// Write the contents of the field. Escaping is the responsibility of
+	/// `W`, typically a `pushgenerator::Field`.
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()>;
+}
+
+pub trait FromField: Sized {
+	fn from_field(buf: &[u8]) -> Result<Self, Error>;
+}
+
+impl<'a, T: ToField + ?Sized> ToField for &'a T {
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		(**self).write_to(w)
+	}
+}
+
+fn parse<T: FromStr>(buf: &[u8], err: &'static str) -> Result<T, Error> {
+	match str::from_utf8(buf).ok().and_then(|s| s.parse().ok()) {
+		Some(value) => Ok(value),
+		None => Err(Error::Unspecified(err)),
+	}
+}
+
+macro_rules! display_field {
+	($err:expr; $($t:ty)*) => {
+		$(
+			impl ToField for $t {
+				fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+					write!(w, "{}", self)
+				}
+			}
+
+			impl FromField for $t {
+				fn from_field(buf: &[u8]) -> Result<Self, Error> {
+					parse(buf, $err)
+				}
+			}
+		)*
+	}
+}
+
+display_field!("Invalid integer in field"; i8 i16 i32 i64 i128 isize u8 u16 u32 u64 u128 usize);
+display_field!("Invalid number in field"; f32 f64);
+display_field!("Invalid boolean in field"; bool);
+
+impl ToField for char {
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		let mut buf = [0u8; 4];
+		w.write_all(self.encode_utf8(&mut buf).as_bytes())
+	}
+}
+
+impl FromField for char {
+	fn from_field(buf: &[u8]) -> Result<Self, Error> {
+		let s = try!{str::from_utf8(buf).map_err(|_| Error::Unspecified("Invalid UTF-8 in field"))};
+		let mut chars = s.chars();
+		match (chars.next(), chars.next()) {
+			(Some(ch), None) => Ok(ch),
+			_ => Err(Error::Unspecified("Expected a single character in field")),
+		}
+	}
+}
+
+impl ToField for str {
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		w.write_all(self.as_bytes())
+	}
+}
+
+impl ToField for String {
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		w.write_all(self.as_bytes())
+	}
+}
+
+impl FromField for String {
+	fn from_field(buf: &[u8]) -> Result<Self, Error> {
+		String::from_utf8(buf.to_vec()).map_err(|_| Error::Unspecified("Invalid UTF-8 in field"))
+	}
+}
+
+impl ToField for [u8] {
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		w.write_all(self)
+	}
+}
+
+impl ToField for Vec<u8> {
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		w.write_all(self)
+	}
+}
+
+impl FromField for Vec<u8> {
+	fn from_field(buf: &[u8]) -> Result<Self, Error> {
+		Ok(buf.to_vec())
+	}
+}
+
+impl<T: ToField> ToField for Option<T> {
+	fn write_to<W: Write>(&self, w: &mut W) -> io::Result<()> {
+		match *self {
+			Some(ref value) => value.write_to(w),
+			None => Ok(()),
+		}
+	}
+}
+
+impl<T: FromField> FromField for Option<T> {
+	fn from_field(buf: &[u8]) -> Result<Self, Error> {
+		if buf.len() == 0 {
+			Ok(None)
+		} else {
+			T::from_field(buf).map(Some)
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::fmt::Debug;
+	use std::io::Cursor;
+	use field::*;
+	use pullparser::PullParser;
+	use pushgenerator::PushGenerator;
+
+	fn to_field<T: ToField + ?Sized>(value: &T) -> Vec<u8> {
+		let mut buf = Vec::new();
+		value.write_to(&mut buf).unwrap();
+		buf
+	}
+
+	fn round_trip<T: ToField + FromField + PartialEq + Debug>(value: T) {
+		assert_eq!(value, T::from_field(&to_field(&value)).unwrap());
+	}
+
+	#[test]
+	fn it_encodes_scalars_as_text() {
+		assert_eq!(b"-42".to_vec(), to_field(&-42i32));
+		assert_eq!(b"255".to_vec(), to_field(&255u8));
+		assert_eq!(b"1.5".to_vec(), to_field(&1.5f64));
+		assert_eq!(b"true".to_vec(), to_field(&true));
+		assert_eq!("æ".as_bytes().to_vec(), to_field(&'æ'));
+		assert_eq!(b"katter ape".to_vec(), to_field("katter ape"));
+		assert_eq!(b"".to_vec(), to_field(&None::<u32>));
+		assert_eq!(b"7".to_vec(), to_field(&Some(7u32)));
+	}
+
+	#[test]
+	fn it_round_trips() {
+		round_trip(u64::max_value());
+		round_trip(i128::min_value());
+		round_trip(0.1f32);
+		round_trip(-2.5e100f64);
+		round_trip(false);
+		round_trip('x');
+		round_trip("lol cats\n".to_string());
+		round_trip(vec![0u8, 255, b' ']);
+		round_trip(Some(-1i8));
+		round_trip(None::<String>);
+	}
+
+	#[test]
+	fn it_rejects_invalid_fields() {
+		assert!(u8::from_field(b"256").is_err());
+		assert!(i32::from_field(b"").is_err());
+		assert!(i32::from_field(b" 1").is_err());
+		assert!(bool::from_field(b"1").is_err());
+		assert!(char::from_field(b"ab").is_err());
+		assert!(String::from_field(b"\xff").is_err());
+		assert!(Option::<u8>::from_field(b"x").is_err());
+	}
+
+	#[test]
+	fn it_is_used_by_the_generator_and_parser() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			let mut message = generator.next_message().unwrap();
+			message.write(&7u32).unwrap();
+			message.write("ok go").unwrap();
+			message.write(&None::<bool>).unwrap();
+			message.write(&-0.5f32).unwrap();
+		}
+
+		assert_eq!(b"7 {5}ok go {0} -0.5\n".to_vec(), buffer);
+
+		let mut parser = PullParser::new(Cursor::new(buffer));
+		let mut message = parser.get_message().unwrap().unwrap();
+		assert_eq!(Some(7u32), message.read().unwrap());
+		assert_eq!(Some("ok go".to_string()), message.read().unwrap());
+		assert_eq!(Some(None::<bool>), message.read().unwrap());
+		assert_eq!(Some(-0.5f32), message.read().unwrap());
+		assert_eq!(None::<u8>, message.read().unwrap());
+	}
+}
@@ -11,7 +11,48 @@ Definition][plaintalk].
 [plaintalk]: http://magnushoff.com/plaintalk/introduction-and-definition.html
 */
 
+#[cfg(feature = "codec")]
+extern crate bytes;
+#[cfg(feature = "codec")]
+extern crate tokio_util;
+#[cfg(feature = "async")]
+extern crate futures_io;
+#[cfg(feature = "serde")]
+extern crate serde;
+
+#[cfg(test)]
+extern crate futures;
+#[cfg(test)]
+extern crate tokio;
+#[cfg(all(test, feature = "serde"))]
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
+mod macros;
+
 pub mod pushparser;
 pub mod pullparser;
 
 pub mod pushgenerator;
+pub mod sharedgenerator;
+
+pub mod canonical;
+pub mod encoder;
+
+pub mod field;
+pub mod message;
+
+pub mod command;
+pub mod rpc;
+
+#[cfg(feature = "codec")]
+pub mod codec;
+
+#[cfg(feature = "async")]
+pub mod asyncpullparser;
+#[cfg(feature = "async")]
+pub mod asyncpushgenerator;
+
+#[cfg(feature = "serde")]
+pub mod serialization;
@@ -0,0 +1,138 @@
+/// Build a message from a list of values, each converted to a field with
+/// `field::ToField`.
+///
+/// `plaintalk!(id, "ok", count)` evaluates to an `OwnedMessage`, while
+/// `plaintalk!(generator => id, "ok", count)` writes the message directly to
+/// a `PushGenerator` and evaluates to a `Result<(), pushgenerator::Error>`.
+#[macro_export]
+macro_rules! plaintalk {
+	($generator:expr => $($field:expr),* $(,)?) => {
+		match $generator.next_message() {
+			Ok(mut message) => {
+				let mut result = Ok(());
+				$(
+					if result.is_ok() {
+						result = message.write(&$field);
+					}
+				)*
+				match result {
+					Ok(()) => message.commit(),
+					Err(err) => Err(err),
+				}
+			},
+			Err(err) => Err(err),
+		}
+	};
+	($($field:expr),* $(,)?) => {{
+		let mut message = $crate::message::OwnedMessage::new();
+		$(
+			message.push(&$field);
+		)*
+		message
+	}};
+}
+
+/// Assert that two `OwnedMessage`s are equal. On failure, both messages are
+/// shown in their encoded form, with control characters escaped.
+#[macro_export]
+macro_rules! assert_message {
+	($left:expr, $right:expr) => {
+		match (&$left, &$right) {
+			(left, right) => {
+				if !(*left == *right) {
+					panic!(
+						"assertion failed: `(left == right)`\n  left: `{}`\n right: `{}`",
+						$crate::message::Escaped(left),
+						$crate::message::Escaped(right)
+					)
+				}
+			}
+		}
+	};
+	($left:expr, $right:expr, $($arg:tt)+) => {
+		match (&$left, &$right) {
+			(left, right) => {
+				if !(*left == *right) {
+					panic!(
+						"assertion failed: `(left == right)`\n  left: `{}`\n right: `{}`: {}",
+						$crate::message::Escaped(left),
+						$crate::message::Escaped(right),
+						format_args!($($arg)+)
+					)
+				}
+			}
+		}
+	};
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::{self, Write};
+	use std::panic;
+	use message::OwnedMessage;
+	use pushgenerator::PushGenerator;
+
+	#[test]
+	fn it_builds_owned_messages() {
+		let id = 7u32;
+		let count = Some(3);
+		let message = plaintalk!(id, "ok", count, b"a b".to_vec(), 'x',);
+
+		let expected = OwnedMessage::from(&[&b"7"[..], b"ok", b"3", b"a b", b"x"][..]);
+		assert_eq!(expected, message);
+		assert_eq!(OwnedMessage::new(), plaintalk!());
+	}
+
+	#[test]
+	fn it_writes_to_a_generator() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			plaintalk!(generator => 0, "error", "katter ape").unwrap();
+			plaintalk!(generator => 1.5, false).unwrap();
+		}
+
+		assert_eq!(b"0 error {10}katter ape\n1.5 false\n".to_vec(), buffer);
+	}
+
+	// Accepts `left` bytes, and then fails
+	struct FailingWriter {
+		left: usize,
+	}
+
+	impl Write for FailingWriter {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			if buf.len() > self.left {
+				return Err(io::Error::new(io::ErrorKind::Other, "Full"));
+			}
+			self.left -= buf.len();
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn it_reports_errors_when_ending_the_message() {
+		let mut generator = PushGenerator::new(FailingWriter { left: 4 });
+		assert!(plaintalk!(generator => 0, "ok").is_err());
+		assert!(plaintalk!(generator => 1, "ok").is_err());
+	}
+
+	#[test]
+	fn assert_message_shows_escaped_messages() {
+		assert_message!(plaintalk!(0, "ok"), "0 ok".parse::<OwnedMessage>().unwrap());
+
+		let result = panic::catch_unwind(|| {
+			assert_message!(plaintalk!(0, "a b"), plaintalk!(0, "a\n"), "id {}", 0);
+		});
+		let err = result.unwrap_err();
+		let message = err.downcast_ref::<String>().unwrap();
+		assert!(message.contains(r#"left: `0 {3}a b\n`"#), "{}", message);
+		assert!(message.contains(r#"right: `0 {2}a\n\n`"#), "{}", message);
+		assert!(message.ends_with("id 0"), "{}", message);
+	}
+}
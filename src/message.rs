@@ -0,0 +1,353 @@
+/*!
+An owned PlainTalk message that can be stored, compared, hashed and printed.
+
+All fields are kept in one contiguous buffer together with a table of field
+offsets, so a message costs two allocations regardless of the number of
+fields. When cleared, the allocations are kept, so the same message can be
+reused as a `MessageBuf` for parsing with `PullParser::read_message_into`
+without allocating in the steady state.
+*/
+
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Cursor, Read, Write};
+use std::iter::FromIterator;
+use std::ops::Index;
+use std::slice;
+use std::str::{self, FromStr};
+
+use encoder::{encode_fields_into, encoded_fields_len};
+use field::ToField;
+use pullparser::{self, PullParser};
+use pushgenerator::{self, PushGenerator};
+
+#[derive(Clone, Default, PartialEq, Eq, Hash)]
+pub struct OwnedMessage {
+	data: Vec<u8>,
+	ends: Vec<usize>,
+}
+
+/// An `OwnedMessage` used as reusable storage for parsed messages
+pub type MessageBuf = OwnedMessage;
+
+impl OwnedMessage {
+	pub fn new() -> OwnedMessage {
+		OwnedMessage {
+			data: Vec::new(),
+			ends: Vec::new(),
+		}
+	}
+
+	/// The number of fields
+	pub fn len(&self) -> usize {
+		self.ends.len()
+	}
+
+	pub fn is_empty(&self) -> bool {
+		self.ends.len() == 0
+	}
+
+	pub fn get(&self, index: usize) -> Option<&[u8]> {
+		if index < self.ends.len() {
+			Some(&self[index])
+		} else {
+			None
+		}
+	}
+
+	pub fn iter(&self) -> Fields<'_> {
+		Fields {
+			data: &self.data,
+			start: 0,
+			ends: self.ends.iter(),
+		}
+	}
+
+	/// Remove all fields, keeping the allocated storage
+	pub fn clear(&mut self) {
+		self.data.clear();
+		self.ends.clear();
+	}
+
+	// The data of the field being built, which is completed by end_field
+	pub(crate) fn field_data_mut(&mut self) -> &mut Vec<u8> {
+		&mut self.data
+	}
+
+	pub(crate) fn end_field(&mut self) {
+		self.ends.push(self.data.len());
+	}
+
+	pub fn push_field(&mut self, field: &[u8]) {
+		self.data.extend_from_slice(field);
+		self.ends.push(self.data.len());
+	}
+
+	/// Append a value as a field, encoded as described in the `field` module.
+	pub fn push<T: ToField + ?Sized>(&mut self, value: &T) {
+		value.write_to(&mut self.data).expect("Writing to a Vec never fails");
+		self.ends.push(self.data.len());
+	}
+
+	/// Read the remaining fields of a message from a parser and append them.
+	pub fn read_fields(&mut self, message: &mut pullparser::Message) -> Result<(), pullparser::Error> {
+		while let Some(_) = try!{message.read_field_to_end(&mut self.data)} {
+			self.ends.push(self.data.len());
+		}
+		Ok(())
+	}
+
+	pub fn write_to<W: Write>(&self, generator: &mut PushGenerator<W>) -> Result<(), pushgenerator::Error> {
+		let mut message = try!{generator.next_message()};
+		for field in self {
+			try!{message.write_field(field)};
+		}
+		Ok(())
+	}
+
+	/// The encoded message, including the terminating newline. This is the
+	/// same as `PushGenerator::write_message` generates.
+	pub fn encode(&self) -> Vec<u8> {
+		let mut buf = Vec::with_capacity(encoded_fields_len(self));
+		encode_fields_into(&mut buf, self);
+		buf
+	}
+}
+
+pub struct Fields<'a> {
+	data: &'a [u8],
+	start: usize,
+	ends: slice::Iter<'a, usize>,
+}
+
+impl<'a> Iterator for Fields<'a> {
+	type Item = &'a [u8];
+
+	fn next(&mut self) -> Option<&'a [u8]> {
+		self.ends.next().map(|&end| {
+			let field = &self.data[self.start..end];
+			self.start = end;
+			field
+		})
+	}
+
+	fn size_hint(&self) -> (usize, Option<usize>) {
+		self.ends.size_hint()
+	}
+}
+
+impl<'a> ExactSizeIterator for Fields<'a> {}
+
+impl<'a> IntoIterator for &'a OwnedMessage {
+	type Item = &'a [u8];
+	type IntoIter = Fields<'a>;
+
+	fn into_iter(self) -> Fields<'a> {
+		self.iter()
+	}
+}
+
+impl Index<usize> for OwnedMessage {
+	type Output = [u8];
+
+	fn index(&self, index: usize) -> &[u8] {
+		let start = if index == 0 { 0 } else { self.ends[index - 1] };
+		&self.data[start..self.ends[index]]
+	}
+}
+
+impl<T: AsRef<[u8]>> FromIterator<T> for OwnedMessage {
+	fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> OwnedMessage {
+		let mut message = OwnedMessage::new();
+		for field in iter {
+			message.push_field(field.as_ref());
+		}
+		message
+	}
+}
+
+impl<'a> From<&'a [&'a [u8]]> for OwnedMessage {
+	fn from(fields: &'a [&'a [u8]]) -> OwnedMessage {
+		fields.iter().collect()
+	}
+}
+
+impl From<Vec<Vec<u8>>> for OwnedMessage {
+	fn from(fields: Vec<Vec<u8>>) -> OwnedMessage {
+		fields.iter().collect()
+	}
+}
+
+impl From<OwnedMessage> for Vec<Vec<u8>> {
+	fn from(message: OwnedMessage) -> Vec<Vec<u8>> {
+		message.iter().map(|field| field.to_vec()).collect()
+	}
+}
+
+/// Parses exactly one message. The terminating newline is optional.
+impl<'a> TryFrom<&'a [u8]> for OwnedMessage {
+	type Error = pullparser::Error;
+
+	fn try_from(buf: &'a [u8]) -> Result<OwnedMessage, pullparser::Error> {
+		let terminator: &[u8] = match buf.last() {
+			Some(&b'\n') => b"",
+			_ => b"\n",
+		};
+		let mut parser = PullParser::new(Cursor::new(buf).chain(Cursor::new(terminator)));
+		let message = match try!{parser.read_owned_message()} {
+			Some(message) => message,
+			None => return Err(pullparser::Error::Unspecified("Expected a message")),
+		};
+		match try!{parser.read_owned_message()} {
+			Some(_) => Err(pullparser::Error::Unspecified("Expected a single message")),
+			None => Ok(message),
+		}
+	}
+}
+
+impl FromStr for OwnedMessage {
+	type Err = pullparser::Error;
+
+	fn from_str(s: &str) -> Result<OwnedMessage, pullparser::Error> {
+		OwnedMessage::try_from(s.as_bytes())
+	}
+}
+
+/// Formats the message as PlainTalk, including the terminating newline.
+/// Invalid UTF-8 in a field is replaced with U+FFFD before the field is
+/// encoded, so the output is always valid PlainTalk, but it only matches the
+/// message exactly when all fields are valid UTF-8. Use `encode` to get the
+/// exact bytes.
+impl fmt::Display for OwnedMessage {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		let fields: Vec<_> = self.iter().map(String::from_utf8_lossy).collect();
+		let mut buf = Vec::with_capacity(encoded_fields_len(fields.iter().map(|field| field.as_bytes())));
+		encode_fields_into(&mut buf, fields.iter().map(|field| field.as_bytes()));
+		f.write_str(str::from_utf8(&buf).expect("Encoded UTF-8 fields are UTF-8"))
+	}
+}
+
+/// Displays the encoded message with control characters and non-ASCII bytes
+/// escaped, like `0 {4}a b\n\n`.
+pub struct Escaped<'a>(pub &'a OwnedMessage);
+
+impl<'a> fmt::Display for Escaped<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{}", self.0.encode().escape_ascii())
+	}
+}
+
+struct DebugField<'a>(&'a [u8]);
+
+impl<'a> fmt::Debug for DebugField<'a> {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "\"{}\"", self.0.escape_ascii())
+	}
+}
+
+/// Shows the fields as a list of strings with control characters and
+/// non-ASCII bytes escaped, like `["0", "katter ape", "\xff\n"]`.
+impl fmt::Debug for OwnedMessage {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.debug_list().entries(self.iter().map(DebugField)).finish()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashSet;
+	use std::convert::TryFrom;
+	use std::io::Cursor;
+	use message::*;
+	use pullparser::PullParser;
+	use pushgenerator::PushGenerator;
+
+	#[test]
+	fn it_works() {
+		let message: OwnedMessage = "0 protocol {3}a b".parse().unwrap();
+
+		assert_eq!(3, message.len());
+		assert_eq!(b"0", &message[0]);
+		assert_eq!(b"protocol", &message[1]);
+		assert_eq!(Some(&b"a b"[..]), message.get(2));
+		assert_eq!(None, message.get(3));
+		assert_eq!(vec![&b"0"[..], b"protocol", b"a b"], message.iter().collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn it_can_be_built_field_by_field() {
+		let mut message = OwnedMessage::new();
+		message.push_field(b"0");
+		message.push(&42u32);
+		message.push("");
+		message.push("katter ape");
+
+		assert_eq!(OwnedMessage::from(&[&b"0"[..], b"42", b"", b"katter ape"][..]), message);
+	}
+
+	#[test]
+	fn it_displays_as_plaintalk() {
+		let message = OwnedMessage::from(vec![b"0".to_vec(), b"katter ape".to_vec(), b"".to_vec()]);
+		assert_eq!("0 {10}katter ape {0}\n", message.to_string());
+		assert_eq!(message, message.to_string().parse().unwrap());
+	}
+
+	#[test]
+	fn it_displays_invalid_utf8_as_valid_plaintalk() {
+		let message = OwnedMessage::from(vec![b"0".to_vec(), b"a\xff b".to_vec()]);
+		assert_eq!("0 {6}a\u{fffd} b\n", message.to_string());
+		let parsed: OwnedMessage = message.to_string().parse().unwrap();
+		assert_eq!(OwnedMessage::from(vec![b"0".to_vec(), "a\u{fffd} b".as_bytes().to_vec()]), parsed);
+	}
+
+	#[test]
+	fn it_debugs_with_readable_escapes() {
+		let message = OwnedMessage::from(vec![b"0".to_vec(), b"a b\n".to_vec(), b"\xff".to_vec()]);
+		assert_eq!(r#"["0", "a b\n", "\xff"]"#, format!("{:?}", message));
+	}
+
+	#[test]
+	fn it_can_be_compared_and_hashed() {
+		let a: OwnedMessage = "ab c".parse().unwrap();
+		let b: OwnedMessage = "a bc".parse().unwrap();
+		assert!(a != b);
+
+		let mut set = HashSet::new();
+		set.insert(a.clone());
+		set.insert(b.clone());
+		set.insert("{2}ab c\r\n".parse().unwrap());
+		assert_eq!(2, set.len());
+	}
+
+	#[test]
+	fn it_rejects_anything_but_a_single_message() {
+		assert!(OwnedMessage::try_from(&b""[..]).is_err());
+		assert!(OwnedMessage::try_from(&b"\n"[..]).is_err());
+		assert!(OwnedMessage::try_from(&b"0 a\n1 b\n"[..]).is_err());
+		assert!(OwnedMessage::try_from(&b"0 {5}a\n"[..]).is_err());
+		assert!(OwnedMessage::try_from(&b"\n0 a\n\n"[..]).is_ok());
+	}
+
+	#[test]
+	fn it_converts_to_and_from_the_generator_and_parser() {
+		let messages: Vec<OwnedMessage> = vec![
+			"0 protocol lol".parse().unwrap(),
+			"1 {1}\n {0}".parse().unwrap(),
+		];
+
+		let mut buffer = Vec::new();
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			for message in &messages {
+				message.write_to(&mut generator).unwrap();
+			}
+		}
+		assert_eq!(b"0 protocol lol\n1 {1}\n {0}\n".to_vec(), buffer);
+		assert_eq!(buffer, messages.iter().flat_map(|message| message.encode()).collect::<Vec<u8>>());
+
+		let mut parser = PullParser::new(Cursor::new(buffer));
+		assert_eq!(Some(messages[0].clone()), parser.read_owned_message().unwrap());
+		assert_eq!(Some(messages[1].clone()), parser.read_owned_message().unwrap());
+		assert_eq!(None, parser.read_owned_message().unwrap());
+	}
+}
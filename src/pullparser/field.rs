@@ -109,6 +109,8 @@ impl<'a, 'b> Read for Field<'a, 'b> {
 								}
 							},
 							Some(Ok(SP)) => {
+								// A field separator means this is not an empty line
+								*self.empty = false;
 								*self.message_state = MessageParserState::ExpectingField;
 								self.state = FieldParserState::Done;
 							},
@@ -157,11 +159,15 @@ impl<'a, 'b> Read for Field<'a, 'b> {
 				},
 				FieldParserState::ReadingEscapedBytes(size_left) => {
 					debug_assert!(size_left > 0);
-					// TODO It is possible to reach EOF here. It should be considered an
-					// error, and the parser should stop
 					let try_to_read = cmp::min(buf.len()-cursor, size_left);
 					let read_bytes = try!{reader.read(&mut buf[cursor..cursor+try_to_read])};
 					// TODO An error ^^here should probably terminate the parser
+					if read_bytes == 0 {
+						*self.parser_state = PullParserState::Error("Unexpected EOF");
+						*self.message_state = MessageParserState::Error("Unexpected EOF");
+						self.state = FieldParserState::Error(ErrorKind::InvalidData, "Unexpected EOF");
+						continue;
+					}
 					cursor += read_bytes;
 					self.state = match size_left - read_bytes {
 						0 => FieldParserState::Initial,
@@ -4,6 +4,7 @@ use std::io::Read;
 use super::error::*;
 use super::pullparser::*;
 use super::field::*;
+use field::FromField;
 
 pub enum MessageParserState {
 	ExpectingField,
@@ -115,6 +116,15 @@ impl<'a> Message<'a> {
 		}
 	}
 
+	/// Read a field and convert it as described in the `field` module.
+	pub fn read<T: FromField>(&mut self) -> Result<Option<T>, Error> {
+		let mut buffer = Vec::new();
+		match try!{self.read_field_to_end(&mut buffer)} {
+			Some(_) => T::from_field(&buffer).map(Some),
+			None => Ok(None),
+		}
+	}
+
 	pub fn at_end(&self) -> bool {
 		match self.state {
 			MessageParserState::Done => true,
@@ -1,5 +1,6 @@
 use std::io::Read;
 
+use message::{OwnedMessage, MessageBuf};
 use super::error::*;
 use super::message::*;
 
@@ -22,6 +23,16 @@ impl<R: Read> PullParser<R> {
 		}
 	}
 
+	pub fn get_ref(&self) -> &R {
+		&self.inner
+	}
+
+	/// Mutable access to the underlying reader. Reading from it directly
+	/// will confuse the parser.
+	pub fn get_mut(&mut self) -> &mut R {
+		&mut self.inner
+	}
+
 	pub fn get_message<'x, 'y: 'x+'y>(&'y mut self) -> Result<Option<Message<'x>>, Error> {
 		match self.state {
 			PullParserState::Initial => Ok(Some(Message::new(&mut self.inner, &mut self.state))),
@@ -45,4 +56,40 @@ impl<R: Read> PullParser<R> {
 			Ok(None)
 		}
 	}
+
+	pub fn read_owned_message(&mut self) -> Result<Option<OwnedMessage>, Error> {
+		let mut message = OwnedMessage::new();
+		if try!{self.read_message_into(&mut message)} {
+			Ok(Some(message))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Read the next message into `buf`, replacing its contents. Returns
+	/// `false` at the end of the stream. Reusing the same buffer avoids
+	/// allocations once it has grown to fit the messages.
+	pub fn read_message_into(&mut self, buf: &mut MessageBuf) -> Result<bool, Error> {
+		buf.clear();
+		if let Some(mut message) = try!{self.get_message()} {
+			try!{buf.read_fields(&mut message)};
+		} else {
+			return Ok(false);
+		}
+		if self.at_eof(buf.len() == 1 && buf[0].len() == 0) {
+			buf.clear();
+			return Ok(false);
+		}
+		Ok(true)
+	}
+
+	// When the end of the stream is reached, get_message yields one last
+	// message with a single empty field. That can not be a real message,
+	// since it would be an empty line, which is skipped.
+	fn at_eof(&self, single_empty_field: bool) -> bool {
+		match self.state {
+			PullParserState::Done => single_empty_field,
+			_ => false,
+		}
+	}
 }
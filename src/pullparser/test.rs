@@ -1,4 +1,5 @@
 use std::io::{Read, Cursor};
+use message::MessageBuf;
 use super::*;
 
 fn buffer_message(message: &mut Message) -> Vec<String> {
@@ -74,6 +75,16 @@ fn it_handles_escape_overflow() {
 	assert!(result.is_err());
 }
 
+#[test]
+fn it_fails_on_eof_inside_escape_sequence() {
+	let data = Cursor::new(b"0 {10}katter" as &[u8]);
+	let mut parser = PullParser::new(data);
+	let mut message = parser.get_message().unwrap().unwrap();
+	let mut buffer = String::new();
+	message.get_field().unwrap().unwrap().read_to_string(&mut buffer).unwrap();
+	assert!(message.get_field().unwrap().unwrap().read_to_string(&mut buffer).is_err());
+}
+
 #[test]
 fn it_understands_crlf() {
 	let data = Cursor::new(b"0 ape\r\n1 katt\r\n" as &[u8]);
@@ -232,6 +243,38 @@ fn parser_can_read_a_message() {
 	assert_eq!([b"2".to_vec(), b"lol".to_vec()].to_vec(), parser.read_message().unwrap().unwrap());
 }
 
+#[test]
+fn parser_reads_a_last_empty_message_at_the_end() {
+	let data = Cursor::new(b"0 protocol lol\n" as &[u8]);
+	let mut parser = PullParser::new(data);
+
+	assert_eq!([b"0".to_vec(), b"protocol".to_vec(), b"lol".to_vec()].to_vec(), parser.read_message().unwrap().unwrap());
+	assert_eq!(Some(vec![Vec::new()]), parser.read_message().unwrap());
+	assert_eq!(None, parser.read_message().unwrap());
+}
+
+#[test]
+fn parser_can_read_messages_into_a_reusable_buffer() {
+	let data = Cursor::new(b"0 protocol lol\n1 {3}a b\n\n2 {}\n" as &[u8]);
+	let mut parser = PullParser::new(data);
+	let mut buf = MessageBuf::new();
+
+	assert!(parser.read_message_into(&mut buf).unwrap());
+	assert_eq!(vec![&b"0"[..], b"protocol", b"lol"], buf.iter().collect::<Vec<_>>());
+	let storage = buf[0].as_ptr();
+
+	assert!(parser.read_message_into(&mut buf).unwrap());
+	assert_eq!(vec![&b"1"[..], b"a b"], buf.iter().collect::<Vec<_>>());
+	assert_eq!(storage, buf[0].as_ptr());
+
+	assert!(parser.read_message_into(&mut buf).unwrap());
+	assert_eq!(vec![&b"2"[..], b""], buf.iter().collect::<Vec<_>>());
+	assert_eq!(storage, buf[0].as_ptr());
+
+	assert!(!parser.read_message_into(&mut buf).unwrap());
+	assert!(buf.is_empty());
+}
+
 #[test]
 fn it_accepts_zero_length_escapes() {
 	let data = Cursor::new(b"{} {0} l{000}ol{0000}\n" as &[u8]);
@@ -240,6 +283,16 @@ fn it_accepts_zero_length_escapes() {
 	assert_eq!([b"".to_vec(), b"".to_vec(), b"lol".to_vec()].to_vec(), parser.read_message().unwrap().unwrap());
 }
 
+#[test]
+fn it_accepts_messages_of_empty_fields() {
+	let data = Cursor::new(b"{0} {0}\n{} \na b\n" as &[u8]);
+	let mut parser = PullParser::new(data);
+
+	assert_eq!([b"".to_vec(), b"".to_vec()].to_vec(), parser.read_message().unwrap().unwrap());
+	assert_eq!([b"".to_vec(), b"".to_vec()].to_vec(), parser.read_message().unwrap().unwrap());
+	assert_eq!([b"a".to_vec(), b"b".to_vec()].to_vec(), parser.read_message().unwrap().unwrap());
+}
+
 #[test]
 fn it_ignores_empty_lines() {
 	let data = Cursor::new(b"0 protocol lol\n\n{}\n{0}{00}{000}\n2 lol\n" as &[u8]);
@@ -1,5 +1,10 @@
 use std::convert;
-use std::io::{self, Write};
+use std::error;
+use std::fmt;
+use std::mem;
+use std::io::{self, Read, Write, ErrorKind, IoSlice};
+
+use field::ToField;
 
 #[derive(Debug, Clone)]
 pub enum Error {
@@ -7,6 +12,16 @@ pub enum Error {
 	Unspecified(&'static str),
 }
 
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::Unspecified(ref err) => write!(f, "Unspecified error: {}", err),
+		}
+	}
+}
+
+impl error::Error for Error {}
+
 impl convert::From<io::Error> for Error {
 	fn from(_err: io::Error) -> Error {
 // 		Error::Io(err)
@@ -14,6 +29,23 @@ impl convert::From<io::Error> for Error {
 	}
 }
 
+/// The newline sequence used to terminate messages. PlainTalk parsers accept
+/// both, but some peers only understand CR LF.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineEnding {
+	Lf,
+	CrLf,
+}
+
+impl LineEnding {
+	pub(crate) fn as_bytes(&self) -> &'static [u8] {
+		match *self {
+			LineEnding::Lf => b"\n",
+			LineEnding::CrLf => b"\r\n",
+		}
+	}
+}
+
 enum PushGeneratorState {
 	Initial,
 	GeneratingMessage,
@@ -24,6 +56,10 @@ pub struct PushGenerator<W: Write> {
 	inner: W,
 	state: PushGeneratorState,
 	auto_flush: bool,
+	line_ending: LineEnding,
+	canonical: bool,
+	field_buffer: Vec<u8>,
+	message_buffer: Vec<u8>,
 }
 
 impl<W: Write> PushGenerator<W> {
@@ -32,14 +68,51 @@ impl<W: Write> PushGenerator<W> {
 			inner: inner,
 			state: PushGeneratorState::Initial,
 			auto_flush: true,
+			line_ending: LineEnding::Lf,
+			canonical: false,
+			field_buffer: Vec::new(),
+			message_buffer: Vec::new(),
 		}
 	}
 
+	pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+		self.line_ending = line_ending;
+	}
+
+	pub fn line_ending(&self) -> LineEnding {
+		self.line_ending
+	}
+
+	/// In canonical mode, equal messages always generate identical bytes,
+	/// regardless of how the field contents were chunked when written. See
+	/// the `canonical` module for the exact rules.
+	///
+	/// Field contents are buffered in memory until each field is finished.
+	pub fn set_canonical(&mut self, canonical: bool) {
+		self.canonical = canonical;
+	}
+
+	pub fn canonical(&self) -> bool {
+		self.canonical
+	}
+
 	pub fn next_message<'x, 'y: 'x+'y>(&'y mut self) -> Result<Message<'x, W>, Error> {
+		self.start_message(false)
+	}
+
+	/// Start a message that is accumulated in memory and only written to the
+	/// underlying stream by `Message::commit`. Unless committed, the message
+	/// is discarded when it goes out of scope, so the peer never sees a
+	/// partially generated message.
+	pub fn next_buffered_message<'x, 'y: 'x+'y>(&'y mut self) -> Result<Message<'x, W>, Error> {
+		self.start_message(true)
+	}
+
+	fn start_message<'x, 'y: 'x+'y>(&'y mut self, buffered: bool) -> Result<Message<'x, W>, Error> {
 		match self.state {
 			PushGeneratorState::Initial => {
 				self.state = PushGeneratorState::GeneratingMessage;
-				Ok(Message::new(self))
+				Ok(Message::new(self, buffered))
 			},
 			PushGeneratorState::GeneratingMessage => {
 				Err(Error::Unspecified("Finish message before starting a new one"))
@@ -56,6 +129,17 @@ impl<W: Write> PushGenerator<W> {
 		self.auto_flush
 	}
 
+	/// Write the notification `* <fields>…`, which is a message that is not
+	/// a reply to any request, as described for `rpc::Client`.
+	pub fn write_notification(&mut self, fields: &[&[u8]]) -> Result<(), Error> {
+		let mut message = try!{self.next_message()};
+		try!{message.write_field(b"*")};
+		for field in fields {
+			try!{message.write_field(field)};
+		}
+		message.commit()
+	}
+
 	pub fn write_message(&mut self, msg: &[&[u8]]) -> Result<(), Error> {
 		let mut message = try!{self.next_message()};
 		for &fieldbuf in msg {
@@ -63,6 +147,103 @@ impl<W: Write> PushGenerator<W> {
 		}
 		Ok(())
 	}
+
+	/// Generates the same output as `write_message`, but hands the entire
+	/// message to the underlying stream with as few `write_vectored` calls as
+	/// possible. This saves system calls when writing to unbuffered streams.
+	pub fn write_message_vectored(&mut self, msg: &[&[u8]]) -> Result<(), Error> {
+		match self.state {
+			PushGeneratorState::Initial => {},
+			PushGeneratorState::GeneratingMessage =>
+				return Err(Error::Unspecified("Finish message before starting a new one")),
+			PushGeneratorState::Error(ref err) => return Err(err.clone()),
+		}
+
+		// See the corresponding case in Message::write_end
+		let empty_line = msg.len() == 0 || (msg.len() == 1 && msg[0].len() == 0);
+		if !(self.canonical && empty_line) {
+			let line_ending = self.line_ending.as_bytes();
+			if let Err(_err) = write_fields_vectored(&mut self.inner, msg, line_ending) {
+				let err = Error::Unspecified("Nested error");
+				self.state = PushGeneratorState::Error(err.clone());
+				return Err(err);
+			}
+		}
+
+		if self.auto_flush() {
+			if let Err(_err) = self.inner.flush() {
+				let err = Error::Unspecified("Autoflush failed");
+				self.state = PushGeneratorState::Error(err.clone());
+				return Err(err);
+			}
+		}
+		Ok(())
+	}
+}
+
+const VECTORED_BATCH_SIZE: usize = 16;
+
+fn write_fields_vectored<W: Write>(inner: &mut W, msg: &[&[u8]], line_ending: &[u8]) -> io::Result<()> {
+	if msg.len() == 0 {
+		return inner.write_all(line_ending);
+	}
+
+	let mut headers = [[0u8; MAX_ESCAPE_HEADER_LEN]; VECTORED_BATCH_SIZE];
+	let mut header_lens = [0usize; VECTORED_BATCH_SIZE];
+	let mut written_fields = 0;
+
+	for batch in msg.chunks(VECTORED_BATCH_SIZE) {
+		for (i, field) in batch.iter().enumerate() {
+			header_lens[i] = if should_escape(field) {
+				escape_header(field.len(), &mut headers[i])
+			} else {
+				0
+			};
+		}
+
+		// Each field needs at most a separator, a header and the data
+		let mut slices = [IoSlice::new(&[]); VECTORED_BATCH_SIZE * 3 + 1];
+		let mut count = 0;
+		for (i, field) in batch.iter().enumerate() {
+			if written_fields + i > 0 {
+				slices[count] = IoSlice::new(b" ");
+				count += 1;
+			}
+			if field.len() == 0 {
+				slices[count] = IoSlice::new(b"{0}");
+				count += 1;
+				continue;
+			}
+			if header_lens[i] > 0 {
+				slices[count] = IoSlice::new(&headers[i][0..header_lens[i]]);
+				count += 1;
+			}
+			slices[count] = IoSlice::new(field);
+			count += 1;
+		}
+
+		written_fields += batch.len();
+		if written_fields == msg.len() {
+			slices[count] = IoSlice::new(line_ending);
+			count += 1;
+		}
+
+		try!{write_all_vectored(inner, &mut slices[0..count])};
+	}
+
+	Ok(())
+}
+
+fn write_all_vectored<W: Write>(inner: &mut W, mut slices: &mut [IoSlice]) -> io::Result<()> {
+	while slices.len() > 0 {
+		match inner.write_vectored(slices) {
+			Ok(0) => return Err(io::Error::new(ErrorKind::WriteZero, "failed to write whole message")),
+			Ok(len) => IoSlice::advance_slices(&mut slices, len),
+			Err(ref err) if err.kind() == ErrorKind::Interrupted => {},
+			Err(err) => return Err(err),
+		}
+	}
+	Ok(())
 }
 
 enum MessageState {
@@ -74,13 +255,29 @@ enum MessageState {
 pub struct Message<'a, W: 'a + Write> {
 	inner: &'a mut PushGenerator<W>,
 	state: MessageState,
+	field_count: usize,
+	pending_empty_field: bool,
+	buffered: bool,
+	finished: bool,
 }
 
 impl<'a, W: Write> Message<'a, W> {
-	fn new(inner: &'a mut PushGenerator<W>) -> Message<'a, W> {
+	fn new(inner: &'a mut PushGenerator<W>, buffered: bool) -> Message<'a, W> {
 		Message {
 			inner: inner,
 			state: MessageState::BeforeFirstField,
+			field_count: 0,
+			pending_empty_field: false,
+			buffered: buffered,
+			finished: false,
+		}
+	}
+
+	fn sink(&mut self) -> &mut Write {
+		if self.buffered {
+			&mut self.inner.message_buffer
+		} else {
+			&mut self.inner.inner
 		}
 	}
 
@@ -88,13 +285,17 @@ impl<'a, W: Write> Message<'a, W> {
 		match self.state {
 			MessageState::BeforeFirstField => {
 				self.state = MessageState::GeneratingField;
+				self.field_count += 1;
 				Ok(Field::new(self))
 			},
 			MessageState::AfterFirstField => {
+				let separator: &[u8] = if self.pending_empty_field { b"{0} " } else { b" " };
 				// TODO Handle failure. Should the generator get into a failed
 				// state? Or are we able to try the same operation again?
-				if let Err(_err) = self.inner.inner.write_all(b" ") { return Err(Error::Unspecified("Nested error")); }
+				if let Err(_err) = self.sink().write_all(separator) { return Err(Error::Unspecified("Nested error")); }
+				self.pending_empty_field = false;
 				self.state = MessageState::GeneratingField;
+				self.field_count += 1;
 				Ok(Field::new(self))
 			},
 			MessageState::GeneratingField =>
@@ -111,19 +312,105 @@ impl<'a, W: Write> Message<'a, W> {
 		try!{field.write(buf)};
 		Ok(())
 	}
-}
 
-impl<'a, W: Write> Drop for Message<'a, W> {
-	fn drop(&mut self) {
-		self.inner.state = match self.inner.inner.write_all(&['\n' as u8]) {
-			Ok(()) => PushGeneratorState::Initial,
-			Err(_err) => PushGeneratorState::Error(Error::Unspecified("Nested error")),
+	pub fn write_field_from_reader<R: Read>(&mut self, reader: R) -> Result<u64, Error> {
+		let mut field = try!{self.next_field()};
+		let len = try!{field.copy_from(reader)};
+		Ok(len)
+	}
+
+	/// Write a value as a field, encoded as described in the `field` module.
+	pub fn write<T: ToField + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+		let mut field = try!{self.next_field()};
+		try!{value.write_to(&mut field)};
+		Ok(())
+	}
+
+	/// Write the formatted output as a field, escaping it as necessary
+	/// without buffering it, as in `write_field_fmt(format_args!("{}", x))`.
+	pub fn write_field_fmt(&mut self, args: fmt::Arguments) -> Result<(), Error> {
+		let mut field = try!{self.next_field()};
+		try!{field.write_fmt(args)};
+		Ok(())
+	}
+
+	pub fn write_display_field<T: fmt::Display + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+		self.write_field_fmt(format_args!("{}", value))
+	}
+
+	/// Finish the message. For buffered messages, this is when the message
+	/// is written to the underlying stream. Unbuffered messages are also
+	/// finished when they go out of scope, but then errors are only reported
+	/// on the next use of the generator.
+	pub fn commit(mut self) -> Result<(), Error> {
+		self.finish()
+	}
+
+	/// Discard a buffered message without writing anything. Unbuffered
+	/// messages have already been partially written, so aborting one puts
+	/// the generator in a failed state.
+	pub fn abort(mut self) -> Result<(), Error> {
+		self.finished = true;
+		if self.buffered {
+			self.inner.message_buffer.clear();
+			self.inner.state = PushGeneratorState::Initial;
+			Ok(())
+		} else {
+			let err = Error::Unspecified("Aborted an unbuffered message");
+			self.inner.state = PushGeneratorState::Error(err.clone());
+			Err(err)
+		}
+	}
+
+	fn write_end(&mut self) -> io::Result<()> {
+		// A message consisting of nothing or a single empty field is
+		// indistinguishable from an empty line, which parsers skip
+		let line_ending: &[u8] = if self.inner.canonical && (self.field_count == 0 || self.pending_empty_field) {
+			b""
+		} else {
+			self.inner.line_ending.as_bytes()
 		};
+		if self.buffered {
+			let generator = &mut *self.inner;
+			generator.message_buffer.extend_from_slice(line_ending);
+			let result = generator.inner.write_all(&generator.message_buffer);
+			generator.message_buffer.clear();
+			result
+		} else {
+			self.inner.inner.write_all(line_ending)
+		}
+	}
+
+	fn finish(&mut self) -> Result<(), Error> {
+		self.finished = true;
+		if let Err(_err) = self.write_end() {
+			let err = Error::Unspecified("Nested error");
+			self.inner.state = PushGeneratorState::Error(err.clone());
+			return Err(err);
+		}
+		self.inner.state = PushGeneratorState::Initial;
 		if self.inner.auto_flush() {
 			if let Err(_err) = self.inner.inner.flush() {
-				self.inner.state = PushGeneratorState::Error(Error::Unspecified("Autoflush failed"));
+				let err = Error::Unspecified("Autoflush failed");
+				self.inner.state = PushGeneratorState::Error(err.clone());
+				return Err(err);
 			}
 		}
+		Ok(())
+	}
+}
+
+impl<'a, W: Write> Drop for Message<'a, W> {
+	fn drop(&mut self) {
+		if self.finished {
+			return;
+		}
+		if self.buffered {
+			self.inner.message_buffer.clear();
+			self.inner.state = PushGeneratorState::Initial;
+		} else {
+			let _ = self.finish();
+		}
 	}
 }
 
@@ -139,13 +426,82 @@ impl<'a, 'b, W: Write> Field<'a, 'b, W> {
 			empty: true,
 		}
 	}
+
+	/// Stream everything from `reader` into this field, without knowing the
+	/// total length up front. The data is written in chunks of at most 8 KiB,
+	/// each framed as a separate escape sequence.
+	///
+	/// In canonical mode the field must be written as a single escape
+	/// sequence, so instead of being streamed, the data is buffered in memory
+	/// until the field is finished. To bound the memory used, this fails with
+	/// `ErrorKind::InvalidData` when the field would grow beyond 16 MiB, and
+	/// nothing read from `reader` is added to the field.
+	pub fn copy_from<R: Read>(&mut self, reader: R) -> io::Result<u64> {
+		if self.inner.inner.canonical {
+			let buffer = &mut self.inner.inner.field_buffer;
+			let start = buffer.len();
+			let room = MAX_CANONICAL_FIELD_LEN.saturating_sub(start as u64);
+			// Read one byte more than fits to tell whether the data is too long
+			let result = reader.take(room + 1).read_to_end(buffer);
+			let len = match result {
+				Ok(len) if len as u64 <= room => len,
+				Ok(_) => {
+					buffer.truncate(start);
+					return Err(io::Error::new(ErrorKind::InvalidData, "Field too long to buffer in canonical mode"));
+				},
+				Err(err) => {
+					buffer.truncate(start);
+					return Err(err);
+				},
+			};
+			self.empty = self.empty && (len == 0);
+			return Ok(len as u64);
+		}
+
+		let mut reader = reader;
+
+		let mut buf = [0u8; COPY_CHUNK_SIZE];
+		let mut total: u64 = 0;
+		loop {
+			let len = match reader.read(&mut buf) {
+				Ok(0) => return Ok(total),
+				Ok(len) => len,
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => return Err(err),
+			};
+			let sink = self.inner.sink();
+			try!{write!(sink, "{{{}}}", len)}
+			try!{sink.write_all(&buf[0..len])}
+			self.empty = false;
+			total += len as u64;
+		}
+	}
 }
 
 impl<'a, 'b, W: Write> Drop for Field<'a, 'b, W> {
 	fn drop(&mut self) {
+		if self.inner.inner.canonical {
+			let mut field_buffer = mem::replace(&mut self.inner.inner.field_buffer, Vec::new());
+			{
+				let sink = self.inner.sink();
+				if should_escape(&field_buffer) {
+					let _ = write!(sink, "{{{}}}", field_buffer.len());
+				}
+				// TODO Handle errors. Should an error put the generator into a failed state?
+				let _ = sink.write_all(&field_buffer);
+			}
+			field_buffer.clear();
+			self.inner.inner.field_buffer = field_buffer;
+
+			if self.empty && self.inner.field_count == 1 {
+				// Defer until we know if this field is alone in its message
+				self.inner.pending_empty_field = true;
+				self.empty = false;
+			}
+		}
 		if self.empty {
 			// TODO Handle errors. Should an error put the generator into a failed state?
-			let _ = self.inner.inner.inner.write(b"{0}");
+			let _ = self.inner.sink().write(b"{0}");
 		}
 		self.inner.state = MessageState::AfterFirstField;
 	}
@@ -156,7 +512,21 @@ const SP: u8 = ' ' as u8;
 const CR: u8 = '\r' as u8;
 const LF: u8 = '\n' as u8;
 
-fn should_escape(buf: &[u8]) -> bool {
+const COPY_CHUNK_SIZE: usize = 8192;
+
+// The most `Field::copy_from` buffers in canonical mode
+const MAX_CANONICAL_FIELD_LEN: u64 = 16 * 1024 * 1024;
+
+// "{", up to 20 digits for a 64 bit length, and "}"
+pub(crate) const MAX_ESCAPE_HEADER_LEN: usize = 22;
+
+pub(crate) fn escape_header(len: usize, buf: &mut [u8; MAX_ESCAPE_HEADER_LEN]) -> usize {
+	let mut cursor = &mut buf[..];
+	write!(cursor, "{{{}}}", len).expect("The escape header should always fit");
+	MAX_ESCAPE_HEADER_LEN - cursor.len()
+}
+
+pub(crate) fn should_escape(buf: &[u8]) -> bool {
 	if buf.len() > 100 {
 		true
 	} else {
@@ -164,11 +534,23 @@ fn should_escape(buf: &[u8]) -> bool {
 	}
 }
 
+impl<'a, 'b, W: Write> fmt::Write for Field<'a, 'b, W> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		self.write_all(s.as_bytes()).map_err(|_| fmt::Error)
+	}
+}
+
 impl<'a, 'b, W: Write> Write for Field<'a, 'b, W> {
 	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
 		// TODO Handle errors. Should an error put the generator into a failed state?
 
-		let inner_stream = &mut self.inner.inner.inner;
+		if self.inner.inner.canonical {
+			self.inner.inner.field_buffer.extend_from_slice(buf);
+			self.empty = self.empty && (buf.len() == 0);
+			return Ok(buf.len());
+		}
+
+		let inner_stream = self.inner.sink();
 		if should_escape(buf) {
 			try!{write!(inner_stream, "{{{}}}", buf.len())}
 		}
@@ -184,9 +566,39 @@ impl<'a, 'b, W: Write> Write for Field<'a, 'b, W> {
 
 #[cfg(test)]
 mod test {
-	use std::io::Write;
+	use std::cmp;
+	use std::fmt;
+	use std::io::{self, Write, Cursor, IoSlice};
 	use pushgenerator::*;
 
+	// Accepts at most `max_write` bytes per call and counts the calls
+	struct ThrottledWriter {
+		data: Vec<u8>,
+		max_write: usize,
+		calls: usize,
+	}
+
+	impl Write for ThrottledWriter {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.write_vectored(&[IoSlice::new(buf)])
+		}
+
+		fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+			self.calls += 1;
+			let mut written = 0;
+			for buf in bufs {
+				let len = cmp::min(buf.len(), self.max_write - written);
+				self.data.extend(&buf[0..len]);
+				written += len;
+			}
+			Ok(written)
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
 	#[test]
 	fn it_works() {
 		let mut buffer = Vec::new();
@@ -267,4 +679,293 @@ mod test {
 
 		assert_eq!(b"{0} {0}\n".to_vec(), buffer);
 	}
+
+	#[test]
+	fn it_can_copy_a_field_from_a_reader() {
+		let data = vec![b'x'; 10000];
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+
+			{
+				let mut message = generator.next_message().unwrap();
+				message.write_field(b"0").unwrap();
+				assert_eq!(10000, message.write_field_from_reader(Cursor::new(&data)).unwrap());
+				assert_eq!(0, message.write_field_from_reader(Cursor::new(b"")).unwrap());
+			}
+		}
+
+		let mut expected = b"0 {8192}".to_vec();
+		expected.extend(&data[0..8192]);
+		expected.extend(b"{1808}");
+		expected.extend(&data[8192..]);
+		expected.extend(b" {0}\n");
+		assert_eq!(expected, buffer);
+	}
+
+	#[test]
+	fn it_limits_copied_fields_in_canonical_mode() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			generator.set_canonical(true);
+
+			let mut message = generator.next_message().unwrap();
+			{
+				let mut field = message.next_field().unwrap();
+				field.write_all(b"a b").unwrap();
+				let too_long = io::repeat(b'x').take(MAX_CANONICAL_FIELD_LEN);
+				assert_eq!(ErrorKind::InvalidData, field.copy_from(too_long).unwrap_err().kind());
+				assert_eq!(1, field.copy_from(Cursor::new(b"c")).unwrap());
+			}
+			message.commit().unwrap();
+		}
+
+		assert_eq!(b"{4}a bc\n".to_vec(), buffer);
+	}
+
+	#[test]
+	fn it_can_terminate_messages_with_crlf() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			generator.set_line_ending(LineEnding::CrLf);
+
+			generator.write_message(&[b"0", b"ok"]).unwrap();
+			generator.write_message(&[b"1", b"a\r\nb", b""]).unwrap();
+		}
+
+		assert_eq!(b"0 ok\r\n1 {4}a\r\nb {0}\r\n".to_vec(), buffer);
+	}
+
+	#[test]
+	fn it_escapes_control_characters_with_crlf() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			generator.set_line_ending(LineEnding::CrLf);
+
+			{
+				let mut message = generator.next_message().unwrap();
+				let mut field = message.next_field().unwrap();
+				field.write_all(b"\r").unwrap();
+				field.write_all(b"\n").unwrap();
+			}
+		}
+
+		assert_eq!(b"{1}\r{1}\n\r\n".to_vec(), buffer);
+	}
+
+	#[test]
+	fn canonical_mode_is_independent_of_chunking() {
+		let mut chunked = Vec::new();
+		let mut whole = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut chunked);
+			generator.set_canonical(true);
+
+			{
+				let mut message = generator.next_message().unwrap();
+
+				{
+					let mut field = message.next_field().unwrap();
+					field.write_all(b"0").unwrap();
+				}
+
+				{
+					let mut field = message.next_field().unwrap();
+					field.write_all(b"katter").unwrap();
+					field.write_all(b" ").unwrap();
+					field.write_all(b"ape").unwrap();
+				}
+
+				{
+					let mut field = message.next_field().unwrap();
+					field.write_all(b"lol").unwrap();
+					field.write_all(b"").unwrap();
+					field.write_all(b"cats").unwrap();
+				}
+			}
+		}
+
+		{
+			let mut generator = PushGenerator::new(&mut whole);
+			generator.set_canonical(true);
+			generator.write_message(&[b"0", b"katter ape", b"lolcats"]).unwrap();
+		}
+
+		assert_eq!(b"0 {10}katter ape lolcats\n".to_vec(), chunked);
+		assert_eq!(chunked, whole);
+	}
+
+	#[test]
+	fn canonical_mode_omits_messages_equivalent_to_empty_lines() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			generator.set_canonical(true);
+
+			generator.write_message(&[]).unwrap();
+			generator.write_message(&[b""]).unwrap();
+			generator.write_message(&[b"", b""]).unwrap();
+			generator.write_message(&[b"", b"a"]).unwrap();
+		}
+
+		assert_eq!(b"{0} {0}\n{0} a\n".to_vec(), buffer);
+	}
+
+	#[test]
+	fn buffered_messages_are_written_on_commit() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+
+			{
+				let mut message = generator.next_buffered_message().unwrap();
+				message.write_field(b"0").unwrap();
+				message.write_field(b"katter ape").unwrap();
+				message.commit().unwrap();
+			}
+
+			generator.write_message(&[b"1", b"ok"]).unwrap();
+		}
+
+		assert_eq!(b"0 {10}katter ape\n1 ok\n".to_vec(), buffer);
+	}
+
+	#[test]
+	fn buffered_messages_can_be_aborted() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+
+			{
+				let mut message = generator.next_buffered_message().unwrap();
+				message.write_field(b"0").unwrap();
+				message.write_field(b"half").unwrap();
+				message.abort().unwrap();
+			}
+
+			{
+				let mut message = generator.next_buffered_message().unwrap();
+				message.write_field(b"1").unwrap();
+				message.write_field(b"dropped").unwrap();
+			}
+
+			generator.write_message(&[b"2", b"ok"]).unwrap();
+		}
+
+		assert_eq!(b"2 ok\n".to_vec(), buffer);
+	}
+
+	#[test]
+	fn aborting_an_unbuffered_message_fails_the_generator() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+
+			{
+				let mut message = generator.next_message().unwrap();
+				message.write_field(b"0").unwrap();
+				assert!(message.abort().is_err());
+			}
+
+			assert!(generator.next_message().is_err());
+		}
+
+		assert_eq!(b"0".to_vec(), buffer);
+	}
+
+	#[test]
+	fn vectored_writes_generate_the_same_output() {
+		let long_field = vec![b'x'; 200];
+		let many_fields = vec![&b"a b"[..]; 40];
+		let messages: Vec<Vec<&[u8]>> = vec![
+			vec![b"0", b"error", b"success"],
+			vec![b"", b"katter ape", &long_field, b""],
+			many_fields,
+			vec![],
+		];
+
+		let mut expected = Vec::new();
+		let mut vectored = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut expected);
+			generator.set_line_ending(LineEnding::CrLf);
+			for message in &messages {
+				generator.write_message(message).unwrap();
+			}
+		}
+
+		{
+			let mut generator = PushGenerator::new(&mut vectored);
+			generator.set_line_ending(LineEnding::CrLf);
+			for message in &messages {
+				generator.write_message_vectored(message).unwrap();
+			}
+		}
+
+		assert_eq!(expected, vectored);
+	}
+
+	#[test]
+	fn vectored_writes_use_few_calls_and_handle_partial_writes() {
+		let mut writer = ThrottledWriter { data: Vec::new(), max_write: 1000, calls: 0 };
+		PushGenerator::new(&mut writer).write_message_vectored(&[b"0", b"ok", b"katter ape"]).unwrap();
+		assert_eq!(1, writer.calls);
+		assert_eq!(b"0 ok {10}katter ape\n".to_vec(), writer.data);
+
+		let mut writer = ThrottledWriter { data: Vec::new(), max_write: 3, calls: 0 };
+		PushGenerator::new(&mut writer).write_message_vectored(&[b"0", b"ok", b"katter ape"]).unwrap();
+		assert_eq!(b"0 ok {10}katter ape\n".to_vec(), writer.data);
+	}
+
+	#[test]
+	fn it_can_write_formatted_fields() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+
+			{
+				let mut message = generator.next_message().unwrap();
+				message.write_display_field(&42).unwrap();
+				message.write_display_field("katter ape").unwrap();
+				message.write_field_fmt(format_args!("{}\n{}", "a", 1.5)).unwrap();
+			}
+
+			{
+				let mut message = generator.next_message().unwrap();
+				let mut field = message.next_field().unwrap();
+				fmt::Write::write_fmt(&mut field, format_args!("{}-{}", 1, 2)).unwrap();
+			}
+		}
+
+		assert_eq!(b"42 {10}katter ape {2}a\n1.5\n1-2\n".to_vec(), buffer);
+	}
+
+	#[test]
+	fn formatted_fields_are_canonical_in_canonical_mode() {
+		let mut buffer = Vec::new();
+
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			generator.set_canonical(true);
+
+			let mut message = generator.next_message().unwrap();
+			message.write_field_fmt(format_args!("{} {}", "a", "b")).unwrap();
+		}
+
+		assert_eq!(b"{3}a b\n".to_vec(), buffer);
+	}
 }
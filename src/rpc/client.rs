@@ -0,0 +1,416 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::str;
+use std::time::{Duration, Instant};
+
+use command::ToMessage;
+use message::OwnedMessage;
+use pullparser::{self, PullParser};
+use pushgenerator::{self, PushGenerator};
+use super::error::*;
+
+/// The client side of a connection where each request starts with an id
+/// field and the replies start with the id of the request they answer.
+///
+/// Requests may be pipelined: any number of requests can be sent before
+/// receiving the replies, and replies can arrive in any order. Replies that
+/// arrive while waiting for a different id are kept until asked for.
+///
+/// Error replies, `<id> error <code> <description>`, are returned as
+/// `RemoteError`.
+///
+/// A request can be answered with a stream of replies, `<id> item <fields>…`,
+/// terminated by `<id> end` or by an error reply. Such requests must be sent
+/// with `send_stream`, and the replies are read with `stream`.
+///
+/// Notifications are messages from the server with `*` in place of the id.
+/// They can arrive at any time, and are read along with the replies. They
+/// are passed to the notification handler, if one is set, or queued for
+/// `receive_notification`.
+///
+/// A pending request can be cancelled, which sends `* cancel <id>` and
+/// discards any replies to it, or given a deadline, after which it is
+/// cancelled and reported as `Error::TimedOut`. Deadlines are checked before
+/// reading, and when reading times out, so for a deadline to take effect
+/// while waiting for replies, the reader must have a read timeout, as set by
+/// `TcpStream::set_read_timeout`. The read timeout is only observed between
+/// messages.
+pub struct Client<R, W: Write> {
+	parser: PullParser<Interruptible<R>>,
+	generator: PushGenerator<W>,
+	next_id: u64,
+	pending: HashSet<u64>,
+	streams: HashSet<u64>,
+	deadlines: HashMap<u64, Instant>,
+	timed_out: HashSet<u64>,
+	// Cancelled requests whose last reply has not yet arrived, and whether
+	// they are streams
+	cancelled: HashMap<u64, bool>,
+	ready: VecDeque<(u64, OwnedMessage)>,
+	notifications: VecDeque<OwnedMessage>,
+	notification_handler: Option<Box<FnMut(OwnedMessage) + Send>>,
+}
+
+impl<R: Read, W: Write> Client<R, W> {
+	pub fn new(reader: R, writer: W) -> Client<R, W> {
+		Client {
+			parser: PullParser::new(Interruptible { inner: reader, between_messages: true, after_cr: false }),
+			generator: PushGenerator::new(writer),
+			next_id: 0,
+			pending: HashSet::new(),
+			streams: HashSet::new(),
+			deadlines: HashMap::new(),
+			timed_out: HashSet::new(),
+			cancelled: HashMap::new(),
+			ready: VecDeque::new(),
+			notifications: VecDeque::new(),
+			notification_handler: None,
+		}
+	}
+
+	/// Pass notifications to `handler` as they are read, instead of queueing
+	/// them. Any queued notifications are passed to it right away. To
+	/// deliver notifications to a channel, pass a closure that sends them.
+	pub fn set_notification_handler<F: FnMut(OwnedMessage) + Send + 'static>(&mut self, mut handler: F) {
+		for notification in self.notifications.drain(..) {
+			handler(notification);
+		}
+		self.notification_handler = Some(Box::new(handler));
+	}
+
+	/// Send a request with the fields written by `write_fields` following a
+	/// new id, and return the id. Nothing is sent if `write_fields` fails.
+	pub fn send_with<F>(&mut self, write_fields: F) -> Result<u64, Error>
+		where F: FnOnce(&mut pushgenerator::Message<W>) -> Result<(), pushgenerator::Error>
+	{
+		let id = self.next_id;
+		{
+			let mut message = try!{self.generator.next_buffered_message()};
+			try!{message.write(&id)};
+			if let Err(err) = write_fields(&mut message) {
+				try!{message.abort()};
+				return Err(Error::Generate(err));
+			}
+			try!{message.commit()};
+		}
+		try!{self.generator.flush()};
+		self.next_id += 1;
+		self.pending.insert(id);
+		Ok(id)
+	}
+
+	pub fn send(&mut self, fields: &[&[u8]]) -> Result<u64, Error> {
+		self.send_with(|message| {
+			for field in fields {
+				try!{message.write_field(field)};
+			}
+			Ok(())
+		})
+	}
+
+	pub fn send_command<C: ToMessage>(&mut self, command: &C) -> Result<u64, Error> {
+		self.send_with(|message| command.write_fields(message))
+	}
+
+	/// Send a request that is answered with a stream of replies.
+	pub fn send_stream(&mut self, fields: &[&[u8]]) -> Result<u64, Error> {
+		let id = try!{self.send(fields)};
+		self.streams.insert(id);
+		Ok(id)
+	}
+
+	/// Cancel the request with the given id if its reply has not arrived by
+	/// `deadline`. This has no effect while waiting for replies unless the
+	/// reader has a read timeout, as described for `Client`.
+	pub fn set_deadline(&mut self, id: u64, deadline: Instant) -> Result<(), Error> {
+		if !self.pending.contains(&id) {
+			return Err(Error::Unspecified("No request with this id is pending"));
+		}
+		self.deadlines.insert(id, deadline);
+		Ok(())
+	}
+
+	/// Cancel the pending request with the given id by sending
+	/// `* cancel <id>`. Any replies to it, including those that have already
+	/// arrived, are discarded.
+	pub fn cancel(&mut self, id: u64) -> Result<(), Error> {
+		if !self.pending.remove(&id) {
+			return Err(Error::Unspecified("No request with this id is pending"));
+		}
+		self.deadlines.remove(&id);
+		let stream = self.streams.remove(&id);
+		self.cancelled.insert(id, stream);
+		self.ready.retain(|&(ready_id, _)| ready_id != id);
+		try!{self.generator.write_notification(&[b"cancel", id.to_string().as_bytes()])};
+		try!{self.generator.flush()};
+		Ok(())
+	}
+
+	/// Wait for the reply to the request with the given id, and return the
+	/// fields following the id.
+	pub fn receive(&mut self, id: u64) -> Result<OwnedMessage, Error> {
+		let reply = try!{self.receive_reply(id)};
+		check_error(reply).map_err(Error::Remote)
+	}
+
+	/// Iterate over the stream of replies to the request with the given id,
+	/// which must have been sent with `send_stream`. The iteration stops at
+	/// the end of the stream, after yielding any error that terminates it.
+	pub fn stream(&mut self, id: u64) -> ReplyStream<'_, R, W> {
+		ReplyStream {
+			client: self,
+			id: id,
+			done: false,
+		}
+	}
+
+	/// Send a request and iterate over the stream of replies.
+	pub fn call_stream(&mut self, fields: &[&[u8]]) -> Result<ReplyStream<'_, R, W>, Error> {
+		let id = try!{self.send_stream(fields)};
+		Ok(self.stream(id))
+	}
+
+	/// Wait for the next reply to any request, in the order they arrived.
+	/// Requests that time out are reported as `Error::TimedOut`.
+	pub fn receive_any(&mut self) -> Result<(u64, Result<OwnedMessage, RemoteError>), Error> {
+		loop {
+			if let Some(id) = self.timed_out.iter().next().cloned() {
+				self.timed_out.remove(&id);
+				return Err(Error::TimedOut(id));
+			}
+			if let Some((id, reply)) = self.ready.pop_front() {
+				return Ok((id, check_error(reply)));
+			}
+			if let Some((id, reply)) = try!{self.read_reply()} {
+				return Ok((id, check_error(reply)));
+			}
+		}
+	}
+
+	/// Send a request and wait for its reply.
+	pub fn call(&mut self, fields: &[&[u8]]) -> Result<OwnedMessage, Error> {
+		let id = try!{self.send(fields)};
+		self.receive(id)
+	}
+
+	/// Send a request and wait for its reply, cancelling the request if the
+	/// reply has not arrived within `timeout`. Without a read timeout on the
+	/// reader, this waits for the reply however long it takes.
+	pub fn call_with_timeout(&mut self, fields: &[&[u8]], timeout: Duration) -> Result<OwnedMessage, Error> {
+		let id = try!{self.send(fields)};
+		try!{self.set_deadline(id, Instant::now() + timeout)};
+		self.receive(id)
+	}
+
+	/// Wait for the next notification, keeping any replies that arrive in
+	/// the meantime. Fails if a notification handler is set.
+	pub fn receive_notification(&mut self) -> Result<OwnedMessage, Error> {
+		if self.notification_handler.is_some() {
+			return Err(Error::Unspecified("Notifications are passed to the notification handler"));
+		}
+		if let Some(notification) = self.notifications.pop_front() {
+			return Ok(notification);
+		}
+		loop {
+			match try!{self.read_message()} {
+				(None, notification) => return Ok(notification),
+				(Some(id), reply) => self.ready.push_back((id, reply)),
+			}
+		}
+	}
+
+	/// The number of requests that have been sent, but whose replies have
+	/// not yet been received.
+	pub fn pending(&self) -> usize {
+		self.pending.len()
+	}
+
+	fn receive_reply(&mut self, id: u64) -> Result<OwnedMessage, Error> {
+		loop {
+			if self.timed_out.remove(&id) {
+				return Err(Error::TimedOut(id));
+			}
+			if let Some(index) = self.ready.iter().position(|&(ready_id, _)| ready_id == id) {
+				return Ok(self.ready.remove(index).unwrap().1);
+			}
+			if !self.pending.contains(&id) {
+				return Err(Error::Unspecified("No request with this id is pending"));
+			}
+			if let Some(reply) = try!{self.read_reply()} {
+				self.ready.push_back(reply);
+			}
+		}
+	}
+
+	// Read a reply, handling any notifications that come before it. Returns
+	// `None` if any requests time out first.
+	fn read_reply(&mut self) -> Result<Option<(u64, OwnedMessage)>, Error> {
+		if self.pending.is_empty() {
+			return Err(Error::Unspecified("No requests are pending"));
+		}
+		loop {
+			if try!{self.expire_deadlines()} {
+				return Ok(None);
+			}
+			match self.read_message() {
+				Ok((None, notification)) => match self.notification_handler {
+					Some(ref mut handler) => handler(notification),
+					None => self.notifications.push_back(notification),
+				},
+				Ok((Some(id), reply)) => return Ok(Some((id, reply))),
+				Err(Error::Parse(pullparser::Error::Io(ref err))) if is_timeout(err) => (),
+				Err(err) => return Err(err),
+			}
+		}
+	}
+
+	// Cancel the requests whose deadline has passed. Returns whether there
+	// were any.
+	fn expire_deadlines(&mut self) -> Result<bool, Error> {
+		let now = Instant::now();
+		let expired: Vec<u64> = self.deadlines.iter()
+			.filter(|&(_, &deadline)| deadline <= now)
+			.map(|(&id, _)| id)
+			.collect();
+		for &id in &expired {
+			try!{self.cancel(id)};
+			self.timed_out.insert(id);
+		}
+		Ok(!expired.is_empty())
+	}
+
+	// Read a notification, or a reply and remove its id from the set of
+	// pending requests unless it is an item in a stream. The id is `None`
+	// for notifications. Replies to cancelled requests are skipped.
+	fn read_message(&mut self) -> Result<(Option<u64>, OwnedMessage), Error> {
+		loop {
+			self.parser.get_mut().expect_message();
+			let message = match try!{self.parser.read_owned_message()} {
+				Some(message) => message,
+				None => return Err(Error::Closed),
+			};
+			if &message[0] == b"*" {
+				return Ok((None, message.iter().skip(1).collect()));
+			}
+			let id = str::from_utf8(&message[0]).ok().and_then(|id| id.parse().ok());
+			let last = message.get(1) != Some(&b"item"[..]);
+			match id {
+				Some(id) if self.pending.contains(&id) => {
+					if last || !self.streams.contains(&id) {
+						self.pending.remove(&id);
+						self.streams.remove(&id);
+						self.deadlines.remove(&id);
+					}
+					return Ok((Some(id), message.iter().skip(1).collect()));
+				},
+				Some(id) if self.cancelled.contains_key(&id) => {
+					if last || !self.cancelled[&id] {
+						self.cancelled.remove(&id);
+					}
+				},
+				_ => return Err(Error::UnknownId(message[0].to_vec())),
+			}
+		}
+	}
+}
+
+// Passes on read timeouts only between messages, which is where the parser
+// can resume after an error. Within a message, the read is retried. Empty
+// lines do not start a message, but the parser must see the LF following a
+// CR without interruption.
+struct Interruptible<R> {
+	inner: R,
+	between_messages: bool,
+	after_cr: bool,
+}
+
+impl<R> Interruptible<R> {
+	fn expect_message(&mut self) {
+		self.between_messages = true;
+		self.after_cr = false;
+	}
+}
+
+impl<R: Read> Read for Interruptible<R> {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		loop {
+			match self.inner.read(buf) {
+				Err(ref err) if is_timeout(err) && (!self.between_messages || self.after_cr) => continue,
+				Ok(read) => {
+					for &byte in &buf[..read] {
+						if !self.between_messages {
+							break;
+						}
+						match (self.after_cr, byte) {
+							(false, b'\r') => self.after_cr = true,
+							(_, b'\n') => self.after_cr = false,
+							_ => self.between_messages = false,
+						}
+					}
+					return Ok(read);
+				},
+				result => return result,
+			}
+		}
+	}
+}
+
+fn is_timeout(err: &io::Error) -> bool {
+	err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut
+}
+
+// Also used by `ThreadedClient`
+pub(super) fn check_error(reply: OwnedMessage) -> Result<OwnedMessage, RemoteError> {
+	match RemoteError::from_reply(&reply) {
+		Some(err) => Err(err),
+		None => Ok(reply),
+	}
+}
+
+// Turn a reply in a stream into the next item of the iteration, setting
+// `done` unless it is an item. Also used by `PendingRequest`.
+pub(super) fn stream_item(reply: OwnedMessage, done: &mut bool) -> Option<Result<OwnedMessage, Error>> {
+	if reply.get(0) == Some(&b"item"[..]) {
+		return Some(Ok(reply.iter().skip(1).collect()));
+	}
+	*done = true;
+	if reply.get(0) == Some(&b"end"[..]) && reply.len() == 1 {
+		return None;
+	}
+	match check_error(reply) {
+		Err(err) => Some(Err(Error::Remote(err))),
+		Ok(_) => Some(Err(Error::Unspecified("Invalid reply in a stream"))),
+	}
+}
+
+/// The replies to a request sent with `Client::send_stream`. Replies that are
+/// not read before the stream is dropped are kept, and returned by
+/// `Client::receive_any`.
+pub struct ReplyStream<'c, R: 'c, W: 'c + Write> {
+	client: &'c mut Client<R, W>,
+	id: u64,
+	done: bool,
+}
+
+impl<'c, R: Read, W: Write> ReplyStream<'c, R, W> {
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+}
+
+impl<'c, R: Read, W: Write> Iterator for ReplyStream<'c, R, W> {
+	type Item = Result<OwnedMessage, Error>;
+
+	fn next(&mut self) -> Option<Result<OwnedMessage, Error>> {
+		if self.done {
+			return None;
+		}
+		match self.client.receive_reply(self.id) {
+			Ok(reply) => stream_item(reply, &mut self.done),
+			Err(err) => {
+				self.done = true;
+				Some(Err(err))
+			},
+		}
+	}
+}
@@ -0,0 +1,135 @@
+use std::convert;
+use std::error;
+use std::fmt;
+use std::io::{self, Write};
+
+use message::OwnedMessage;
+use pullparser;
+use pushgenerator::{self, PushGenerator};
+
+#[derive(Debug)]
+pub enum Error {
+	Parse(pullparser::Error),
+	Generate(pushgenerator::Error),
+	/// A reply with an id that does not match any pending request
+	UnknownId(Vec<u8>),
+	/// The connection was closed with requests still pending
+	Closed,
+	/// The peer replied with an error
+	Remote(RemoteError),
+	/// The deadline of the request with this id passed before its reply
+	/// arrived, and the request was cancelled
+	TimedOut(u64),
+	Unspecified(&'static str),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::Parse(ref err) => write!(f, "Parse error: {}", err),
+			Error::Generate(ref err) => write!(f, "Generator error: {}", err),
+			Error::UnknownId(ref id) => write!(f, "Reply with unknown id: {}", id.escape_ascii()),
+			Error::Closed => write!(f, "Connection closed"),
+			Error::Remote(ref err) => write!(f, "Remote error: {}", err),
+			Error::TimedOut(id) => write!(f, "Request {} timed out", id),
+			Error::Unspecified(ref err) => write!(f, "Unspecified error: {}", err),
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			Error::Parse(ref err) => Some(err),
+			Error::Generate(ref err) => Some(err),
+			Error::Remote(ref err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl convert::From<pullparser::Error> for Error {
+	fn from(err: pullparser::Error) -> Error {
+		Error::Parse(err)
+	}
+}
+
+impl convert::From<pushgenerator::Error> for Error {
+	fn from(err: pushgenerator::Error) -> Error {
+		Error::Generate(err)
+	}
+}
+
+impl convert::From<io::Error> for Error {
+	fn from(err: io::Error) -> Error {
+		Error::Generate(pushgenerator::Error::from(err))
+	}
+}
+
+/// An error reply, `<id> error <code> <description>`. The code is a short
+/// machine readable string, such as `unknown-verb`, and the description is
+/// for humans.
+///
+/// Use `PushGenerator::write_error` or `PushGenerator::write_error_from`,
+/// defined below, to send error replies. `Client` turns them into `Error::Remote`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct RemoteError {
+	pub code: String,
+	pub description: String,
+}
+
+impl RemoteError {
+	pub fn new(code: &str, description: &str) -> RemoteError {
+		RemoteError {
+			code: code.to_string(),
+			description: description.to_string(),
+		}
+	}
+
+	/// Parse the fields of a reply following the id, if it is an error
+	/// reply. Fields following the description are ignored.
+	pub fn from_reply(reply: &OwnedMessage) -> Option<RemoteError> {
+		if reply.get(0) != Some(&b"error"[..]) {
+			return None;
+		}
+		let field = |index| String::from_utf8_lossy(reply.get(index).unwrap_or(b"")).into_owned();
+		Some(RemoteError {
+			code: field(1),
+			description: field(2),
+		})
+	}
+}
+
+impl fmt::Display for RemoteError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "{} ({})", self.description, self.code)
+	}
+}
+
+impl error::Error for RemoteError {}
+
+impl<W: Write> PushGenerator<W> {
+	/// Write the error reply `<id> error <code> <description>`.
+	pub fn write_error<D: fmt::Display + ?Sized>(&mut self, id: &[u8], code: &str, description: &D) -> Result<(), pushgenerator::Error> {
+		let mut message = try!{self.next_message()};
+		try!{message.write_field(id)};
+		try!{message.write_field(b"error")};
+		try!{message.write(code)};
+		try!{message.write_display_field(description)};
+		message.commit()
+	}
+
+	/// Write an error reply describing `err`. The code is taken from `err`
+	/// if it is a `RemoteError`. Otherwise, it is `bad-request` for
+	/// `pullparser::Error`, which typically comes from invalid arguments,
+	/// and `failed` for anything else.
+	pub fn write_error_from(&mut self, id: &[u8], err: &(error::Error + 'static)) -> Result<(), pushgenerator::Error> {
+		if let Some(err) = err.downcast_ref::<RemoteError>() {
+			self.write_error(id, &err.code, &err.description)
+		} else if err.is::<pullparser::Error>() {
+			self.write_error(id, "bad-request", err)
+		} else {
+			self.write_error(id, "failed", err)
+		}
+	}
+}
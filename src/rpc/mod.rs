@@ -0,0 +1,28 @@
+/*!
+Request/response on top of PlainTalk, following the convention that every
+request starts with an id field, and that the reply to a request starts with
+the same id, as in `0 protocol lol` answered by `0 ok`. Error replies have
+the form `<id> error <code> <description>`, and messages that are not replies
+to any request, notifications, have `*` in place of the id.
+
+A request can also be answered with a stream of replies, each of the form
+`<id> item <fields>…`, terminated by `<id> end` or by an error reply.
+
+The client cancels a request with the notification `* cancel <id>`. The
+server may still reply to it, but the reply is ignored.
+*/
+
+mod error;
+mod client;
+mod router;
+mod negotiation;
+mod threaded;
+
+pub use self::error::{Error, RemoteError};
+pub use self::client::{Client, ReplyStream};
+pub use self::router::{Arguments, CancellationToken, HandlerResult, Reply, Router};
+pub use self::negotiation::{accept, BeforeNegotiation};
+pub use self::threaded::{PendingRequest, ThreadedClient};
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,89 @@
+use std::io::{Read, Write};
+use std::str;
+
+use pullparser::PullParser;
+use pushgenerator::PushGenerator;
+use super::client::Client;
+use super::error::*;
+
+/// What the server does with requests that arrive before a protocol has
+/// been agreed on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BeforeNegotiation {
+	/// Reply with an error and keep waiting for a `protocol` request
+	Reject,
+	/// Discard them without replying
+	Ignore,
+	/// Stop negotiating and fail
+	Fail,
+}
+
+impl<R: Read, W: Write> Client<R, W> {
+	/// Propose protocols to the server, in order of preference, with the
+	/// request `<id> protocol <name>…`. Returns the protocol the server
+	/// picked. If it supports none of them, the error is `Error::Remote` with
+	/// the code `unsupported-protocol`.
+	pub fn negotiate(&mut self, protocols: &[&str]) -> Result<String, Error> {
+		let id = try!{self.send_with(|message| {
+			try!{message.write_field(b"protocol")};
+			for protocol in protocols {
+				try!{message.write(*protocol)};
+			}
+			Ok(())
+		})};
+		let reply = try!{self.receive(id)};
+		match reply.get(0) {
+			Some(b"protocol") if reply.len() == 2 => {
+				match str::from_utf8(&reply[1]) {
+					Ok(protocol) if protocols.contains(&protocol) => Ok(protocol.to_string()),
+					_ => Err(Error::Unspecified("The server picked a protocol that was not proposed")),
+				}
+			},
+			_ => Err(Error::Unspecified("Invalid reply to protocol negotiation")),
+		}
+	}
+}
+
+/// Wait for a client to propose protocols with `<id> protocol <name>…`, and
+/// pick the first proposed protocol that is in `supported`, replying with
+/// `<id> protocol <name>`. If none is supported, reply with an error and
+/// keep waiting for another proposal. Returns the agreed protocol.
+///
+/// Notifications, with the id `*`, are never replied to, so they are
+/// discarded regardless of `before_negotiation`.
+pub fn accept<R: Read, W: Write>(
+	parser: &mut PullParser<R>,
+	generator: &mut PushGenerator<W>,
+	supported: &[&str],
+	before_negotiation: BeforeNegotiation,
+) -> Result<String, Error> {
+	loop {
+		let message = match try!{parser.read_owned_message()} {
+			Some(message) => message,
+			None => return Err(Error::Closed),
+		};
+		if &message[0] == b"*" {
+			continue;
+		}
+
+		if message.get(1) != Some(&b"protocol"[..]) {
+			match before_negotiation {
+				BeforeNegotiation::Reject => try!{generator.write_error(&message[0], "not-negotiated", "Protocol not negotiated")},
+				BeforeNegotiation::Ignore => (),
+				BeforeNegotiation::Fail => return Err(Error::Unspecified("Request before protocol negotiation")),
+			}
+			continue;
+		}
+
+		let picked = message.iter().skip(2)
+			.filter_map(|proposed| supported.iter().find(|&&protocol| protocol.as_bytes() == proposed))
+			.next();
+		match picked {
+			Some(protocol) => {
+				try!{generator.write_message(&[&message[0], b"protocol", protocol.as_bytes()])};
+				return Ok(protocol.to_string());
+			},
+			None => try!{generator.write_error(&message[0], "unsupported-protocol", "Unsupported protocol")},
+		}
+	}
+}
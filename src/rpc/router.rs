@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+use std::error;
+use std::io::{Read, Write};
+use std::iter::FromIterator;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use command::{self, ToMessage};
+use field::FromField;
+use message::{self, OwnedMessage};
+use pullparser::{self, PullParser};
+use pushgenerator::{self, PushGenerator};
+use super::error::*;
+
+/// What a handler returns. Any error is turned into an error reply.
+pub type HandlerResult = Result<(), Box<error::Error>>;
+
+type Handler<'h, W> = Box<FnMut(&mut Arguments, &mut Reply<W>) -> HandlerResult + 'h>;
+
+// The number of requests `Router::serve_threaded` reads ahead of the one
+// being handled
+const READ_AHEAD: usize = 16;
+
+/// The server side of the request/response convention of `Client`.
+///
+/// Requests are dispatched on the verb, which is the field following the
+/// id, to the handler registered for it. Requests with an unknown verb,
+/// and requests where the handler fails before replying, get an error
+/// reply as written by `PushGenerator::write_error_from`. Handlers can
+/// return a `RemoteError` to choose the error code.
+///
+/// Handlers that reply with a stream of items, using `Reply::send_item`,
+/// need not terminate it: the router sends `<id> end` when the handler
+/// returns, or the error reply if it fails.
+///
+/// The client can cancel a request with the notification `* cancel <id>`,
+/// which handlers observe through `Reply::cancellation`. Since `serve`
+/// handles one request at a time, cancellations have no effect there;
+/// `serve_threaded` reads requests on a separate thread so that they are
+/// seen while a handler is running.
+pub struct Router<'h, W: Write> {
+	handlers: HashMap<Vec<u8>, Handler<'h, W>>,
+	cancellations: Cancellations,
+}
+
+impl<'h, W: Write> Router<'h, W> {
+	pub fn new() -> Router<'h, W> {
+		Router {
+			handlers: HashMap::new(),
+			cancellations: Cancellations::default(),
+		}
+	}
+
+	/// Handle requests with the given verb with `handler`, replacing any
+	/// handler already registered for it.
+	pub fn add<F>(&mut self, verb: &str, handler: F) -> &mut Router<'h, W>
+		where F: FnMut(&mut Arguments, &mut Reply<W>) -> HandlerResult + 'h
+	{
+		self.handlers.insert(verb.as_bytes().to_vec(), Box::new(handler));
+		self
+	}
+
+	/// Handle all requests until the end of the stream.
+	///
+	/// Cancellations have no effect here: a request is read only after the
+	/// previous one has been handled, so a `* cancel` notification can only
+	/// name a request that is already finished or not yet read. Use
+	/// `serve_threaded` to support cancellation.
+	pub fn serve<R: Read>(&mut self, parser: &mut PullParser<R>, generator: &mut PushGenerator<W>) -> Result<(), Error> {
+		while let Some(mut message) = try!{parser.get_message()} {
+			try!{self.handle(&mut message, generator)};
+		}
+		Ok(())
+	}
+
+	/// Handle all requests until the end of the stream, reading them on a
+	/// separate thread so that cancellations are observed while a handler is
+	/// running.
+	///
+	/// The parser is moved to the reader thread, which is not joined. When
+	/// this returns with an error, the reader thread is left blocked on the
+	/// stream, and it stops at the end of the stream, at a read error or
+	/// when it has read the next request.
+	pub fn serve_threaded<R: Read + Send + 'static>(&mut self, mut parser: PullParser<R>, generator: &mut PushGenerator<W>) -> Result<(), Error> {
+		let cancellations = self.cancellations.clone();
+		let (sender, receiver) = mpsc::sync_channel(READ_AHEAD);
+		thread::spawn(move || loop {
+			let message = match parser.read_owned_message() {
+				Ok(Some(message)) => message,
+				Ok(None) => break,
+				Err(err) => {
+					let _ = sender.send(Err(err));
+					break;
+				},
+			};
+			if &message[0] == b"*" {
+				cancellations.notified(&message.iter().skip(1).collect());
+				continue;
+			}
+			cancellations.register(&message[0]);
+			if sender.send(Ok(message)).is_err() {
+				break;
+			}
+		});
+
+		for message in receiver {
+			let message = try!{message};
+			let mut fields = message.iter();
+			let id = fields.next().unwrap_or(b"");
+			let verb = fields.next();
+			let mut arguments = Arguments { source: Source::Owned(fields) };
+			try!{self.dispatch(id, verb, &mut arguments, generator)};
+		}
+		Ok(())
+	}
+
+	/// Handle a single request.
+	pub fn handle(&mut self, message: &mut pullparser::Message, generator: &mut PushGenerator<W>) -> Result<(), Error> {
+		let mut id = Vec::new();
+		try!{message.read_field_to_end(&mut id)};
+		if id.is_empty() && message.at_end() {
+			// The end of the stream
+			return Ok(());
+		}
+		if id == b"*" {
+			let mut notification = OwnedMessage::new();
+			try!{notification.read_fields(message)};
+			self.cancellations.notified(&notification);
+			return Ok(());
+		}
+
+		let mut verb = Vec::new();
+		let verb = match try!{message.read_field_to_end(&mut verb)} {
+			Some(_) => Some(verb),
+			None => None,
+		};
+		let mut arguments = Arguments { source: Source::Parser(message) };
+		self.dispatch(&id, verb.as_ref().map(|verb| &verb[..]), &mut arguments, generator)
+	}
+
+	// Run the handler for a request, and then write the error reply or the
+	// end of the stream as necessary
+	fn dispatch(&mut self, id: &[u8], verb: Option<&[u8]>, arguments: &mut Arguments, generator: &mut PushGenerator<W>) -> Result<(), Error> {
+		let cancellation = self.cancellations.register(id);
+		let (result, replied, streaming) = {
+			let mut reply = Reply {
+				generator: generator,
+				id: id,
+				cancellation: cancellation,
+				replied: false,
+				streaming: false,
+			};
+			let result = match verb.and_then(|verb| self.handlers.get_mut(verb)) {
+				Some(handler) => handler(arguments, &mut reply),
+				None if verb.is_none() => Err(RemoteError::new("missing-verb", "Missing verb").into()),
+				None => Err(RemoteError::new("unknown-verb", "Unknown verb").into()),
+			};
+			(result, reply.replied, reply.streaming)
+		};
+		self.cancellations.remove(id);
+		try!{arguments.ignore_rest()};
+
+		match result {
+			Err(ref err) if !replied || streaming =>
+				Ok(try!{generator.write_error_from(id, &**err)}),
+			Ok(()) if streaming =>
+				Ok(try!{generator.write_message(&[id, b"end"])}),
+			_ => Ok(()),
+		}
+	}
+}
+
+/// Typed access to the arguments of a request, which are the fields
+/// following the verb, encoded as described in the `field` module.
+pub struct Arguments<'m, 'a: 'm> {
+	source: Source<'m, 'a>,
+}
+
+// `Router::handle` reads the arguments from the parser, while
+// `Router::serve_threaded` has read the whole request already
+enum Source<'m, 'a: 'm> {
+	Parser(&'m mut pullparser::Message<'a>),
+	Owned(message::Fields<'m>),
+}
+
+impl<'m, 'a> Arguments<'m, 'a> {
+	pub fn argument<T: FromField>(&mut self) -> Result<T, pullparser::Error> {
+		match self.source {
+			Source::Parser(ref mut message) => command::read_argument(message),
+			Source::Owned(ref mut fields) => match fields.next() {
+				Some(field) => T::from_field(field),
+				None => Err(pullparser::Error::Unspecified("Missing argument")),
+			},
+		}
+	}
+
+	/// Read an argument that is `None` when it is empty or left out.
+	pub fn optional<T: FromField>(&mut self) -> Result<Option<T>, pullparser::Error> {
+		match self.source {
+			Source::Parser(ref mut message) => command::read_optional(message),
+			Source::Owned(ref mut fields) => match fields.next() {
+				Some(field) => Option::<T>::from_field(field),
+				None => Ok(None),
+			},
+		}
+	}
+
+	/// Read all the remaining arguments.
+	pub fn rest<T: FromField, C: FromIterator<T>>(&mut self) -> Result<C, pullparser::Error> {
+		match self.source {
+			Source::Parser(ref mut message) => command::read_varargs(message),
+			Source::Owned(ref mut fields) => fields.map(T::from_field).collect(),
+		}
+	}
+
+	/// Fail if there are arguments left. Otherwise, extra arguments are
+	/// ignored.
+	pub fn expect_end(&self) -> Result<(), pullparser::Error> {
+		match self.source {
+			Source::Parser(ref message) => command::expect_end(message),
+			Source::Owned(ref fields) if fields.len() == 0 => Ok(()),
+			Source::Owned(_) => Err(pullparser::Error::Unspecified("Too many arguments")),
+		}
+	}
+
+	/// Read the next argument as is, appending it to `buf`. Returns the
+	/// length of the argument, or `None` when there are no more.
+	pub fn read_field_to_end(&mut self, buf: &mut Vec<u8>) -> Result<Option<usize>, pullparser::Error> {
+		match self.source {
+			Source::Parser(ref mut message) => message.read_field_to_end(buf),
+			Source::Owned(ref mut fields) => Ok(fields.next().map(|field| {
+				buf.extend_from_slice(field);
+				field.len()
+			})),
+		}
+	}
+
+	fn ignore_rest(&mut self) -> Result<(), pullparser::Error> {
+		match self.source {
+			Source::Parser(ref mut message) => message.ignore_rest(),
+			Source::Owned(_) => Ok(()),
+		}
+	}
+}
+
+/// Writes replies to one request, each starting with the id of the request.
+pub struct Reply<'r, W: 'r + Write> {
+	generator: &'r mut PushGenerator<W>,
+	id: &'r [u8],
+	cancellation: CancellationToken,
+	replied: bool,
+	streaming: bool,
+}
+
+impl<'r, W: Write> Reply<'r, W> {
+	pub fn id(&self) -> &[u8] {
+		self.id
+	}
+
+	/// The token that tells whether the client has cancelled this request.
+	pub fn cancellation(&self) -> &CancellationToken {
+		&self.cancellation
+	}
+
+	/// Send a reply with the fields written by `write_fields` following the
+	/// id. Nothing is sent if `write_fields` fails.
+	pub fn send_with<F>(&mut self, write_fields: F) -> Result<(), pushgenerator::Error>
+		where F: FnOnce(&mut pushgenerator::Message<W>) -> Result<(), pushgenerator::Error>
+	{
+		if self.streaming {
+			return Err(pushgenerator::Error::Unspecified("Can not send a single reply after streaming items"));
+		}
+		try!{self.write_reply(None, write_fields)};
+		self.replied = true;
+		Ok(())
+	}
+
+	pub fn send(&mut self, fields: &[&[u8]]) -> Result<(), pushgenerator::Error> {
+		self.send_with(|message| {
+			for field in fields {
+				try!{message.write_field(field)};
+			}
+			Ok(())
+		})
+	}
+
+	pub fn send_command<C: ToMessage>(&mut self, command: &C) -> Result<(), pushgenerator::Error> {
+		self.send_with(|message| command.write_fields(message))
+	}
+
+	/// Reply with a stream of items, which may be empty. This is implied by
+	/// sending an item. The stream is terminated by the router.
+	pub fn start_stream(&mut self) -> Result<(), pushgenerator::Error> {
+		if self.replied && !self.streaming {
+			return Err(pushgenerator::Error::Unspecified("Can not stream items after a single reply"));
+		}
+		self.replied = true;
+		self.streaming = true;
+		Ok(())
+	}
+
+	/// Send an item in a stream of replies, `<id> item <fields>…`, with the
+	/// fields written by `write_fields`. Nothing is sent if `write_fields`
+	/// fails.
+	pub fn send_item_with<F>(&mut self, write_fields: F) -> Result<(), pushgenerator::Error>
+		where F: FnOnce(&mut pushgenerator::Message<W>) -> Result<(), pushgenerator::Error>
+	{
+		try!{self.start_stream()};
+		self.write_reply(Some(b"item"), write_fields)
+	}
+
+	pub fn send_item(&mut self, fields: &[&[u8]]) -> Result<(), pushgenerator::Error> {
+		self.send_item_with(|message| {
+			for field in fields {
+				try!{message.write_field(field)};
+			}
+			Ok(())
+		})
+	}
+
+	/// Send a notification, which is not a reply to this request.
+	pub fn notify(&mut self, fields: &[&[u8]]) -> Result<(), pushgenerator::Error> {
+		self.generator.write_notification(fields)
+	}
+
+	fn write_reply<F>(&mut self, marker: Option<&[u8]>, write_fields: F) -> Result<(), pushgenerator::Error>
+		where F: FnOnce(&mut pushgenerator::Message<W>) -> Result<(), pushgenerator::Error>
+	{
+		let mut message = try!{self.generator.next_buffered_message()};
+		try!{message.write_field(self.id)};
+		if let Some(marker) = marker {
+			try!{message.write_field(marker)};
+		}
+		if let Err(err) = write_fields(&mut message) {
+			try!{message.abort()};
+			return Err(err);
+		}
+		message.commit()
+	}
+}
+
+/// Tells whether a request has been cancelled. It can be cloned and passed
+/// to other threads.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+	cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+	pub fn new() -> CancellationToken {
+		CancellationToken::default()
+	}
+
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::SeqCst);
+	}
+
+	pub fn is_cancelled(&self) -> bool {
+		self.cancelled.load(Ordering::SeqCst)
+	}
+
+	/// Fail with the `RemoteError` `cancelled` if the request has been
+	/// cancelled, for returning early from a handler.
+	pub fn check(&self) -> Result<(), RemoteError> {
+		match self.is_cancelled() {
+			true => Err(RemoteError::new("cancelled", "Request cancelled")),
+			false => Ok(()),
+		}
+	}
+}
+
+// The cancellation tokens of the requests that are being handled, or, for
+// `Router::serve_threaded`, have been read
+#[derive(Clone, Default)]
+struct Cancellations {
+	tokens: Arc<Mutex<HashMap<Vec<u8>, CancellationToken>>>,
+}
+
+impl Cancellations {
+	fn register(&self, id: &[u8]) -> CancellationToken {
+		let mut tokens = self.tokens.lock().unwrap();
+		tokens.entry(id.to_vec()).or_insert_with(CancellationToken::new).clone()
+	}
+
+	fn remove(&self, id: &[u8]) {
+		self.tokens.lock().unwrap().remove(id);
+	}
+
+	// Handle a notification from the client, the fields following `*`
+	fn notified(&self, notification: &OwnedMessage) {
+		if notification.len() == 2 && &notification[0] == b"cancel" {
+			if let Some(token) = self.tokens.lock().unwrap().get(&notification[1]) {
+				token.cancel();
+			}
+		}
+	}
+}
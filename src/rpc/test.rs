@@ -0,0 +1,709 @@
+use std::io::{self, PipeReader, PipeWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+use message::OwnedMessage;
+use pullparser::PullParser;
+use pushgenerator::PushGenerator;
+use super::*;
+
+// Connect a client to a server running on a separate thread
+fn connect<F>(server: F) -> (Client<PipeReader, PipeWriter>, thread::JoinHandle<()>)
+	where F: FnOnce(PullParser<PipeReader>, PushGenerator<PipeWriter>) + Send + 'static
+{
+	let (client_reader, server_writer) = io::pipe().unwrap();
+	let (server_reader, client_writer) = io::pipe().unwrap();
+	let server = thread::spawn(move || {
+		server(PullParser::new(server_reader), PushGenerator::new(server_writer))
+	});
+	(Client::new(client_reader, client_writer), server)
+}
+
+fn message(s: &str) -> OwnedMessage {
+	s.parse().unwrap()
+}
+
+fn remote_error<T: ::std::fmt::Debug>(result: Result<T, Error>) -> RemoteError {
+	match result {
+		Err(Error::Remote(err)) => err,
+		x => panic!("Unexpected {:?}", x),
+	}
+}
+
+#[test]
+fn it_routes_pipelined_replies_by_id() {
+	let (mut client, server) = connect(|mut parser, mut generator| {
+		// Reply to three requests in reverse order
+		let mut requests = Vec::new();
+		for _ in 0..3 {
+			requests.push(parser.read_owned_message().unwrap().unwrap());
+		}
+		for request in requests.iter().rev() {
+			generator.write_message(&[&request[0], b"echo", &request[2]]).unwrap();
+		}
+	});
+
+	let a = client.send(&[b"echo", b"a"]).unwrap();
+	let b = client.send(&[b"echo", b"b c"]).unwrap();
+	let c = client.send(&[b"echo", b""]).unwrap();
+	assert_eq!(3, client.pending());
+
+	assert_eq!(message("echo a"), client.receive(a).unwrap());
+	assert_eq!(0, client.pending());
+	assert_eq!((c, Ok(message("echo {0}"))), client.receive_any().unwrap());
+	assert_eq!(message("echo {3}b c"), client.receive(b).unwrap());
+	assert!(client.receive(b).is_err());
+
+	server.join().unwrap();
+	assert!(client.receive_any().is_err());
+}
+
+#[test]
+fn it_reports_unknown_ids() {
+	let (mut client, server) = connect(|mut parser, mut generator| {
+		let request = parser.read_owned_message().unwrap().unwrap();
+		generator.write_message(&[b"17", b"surprise"]).unwrap();
+		generator.write_message(&[b"x", b"surprise"]).unwrap();
+		generator.write_message(&[&request[0], b"ok"]).unwrap();
+	});
+
+	let id = client.send(&[b"ping"]).unwrap();
+	match client.receive(id) {
+		Err(Error::UnknownId(ref unknown)) => assert_eq!(b"17", &unknown[..]),
+		x => panic!("Unexpected {:?}", x),
+	}
+	match client.receive(id) {
+		Err(Error::UnknownId(ref unknown)) => assert_eq!(b"x", &unknown[..]),
+		x => panic!("Unexpected {:?}", x),
+	}
+	assert_eq!(message("ok"), client.receive(id).unwrap());
+	server.join().unwrap();
+}
+
+#[test]
+fn it_reports_a_closed_connection() {
+	let (mut client, server) = connect(|mut parser, _generator| {
+		parser.read_owned_message().unwrap();
+	});
+
+	match client.call(&[b"ping"]) {
+		Err(Error::Closed) => (),
+		x => panic!("Unexpected {:?}", x),
+	}
+	server.join().unwrap();
+}
+
+// Serve with `Router::serve`, or with `Router::serve_threaded`, which
+// dispatches requests that it has read whole
+fn serve_calculator(threaded: bool) -> impl FnOnce(PullParser<PipeReader>, PushGenerator<PipeWriter>) + Send + 'static {
+	move |mut parser, mut generator| {
+		let mut total = 0i64;
+		let mut router = Router::new();
+		router
+			.add("ping", |_args, reply| {
+				try!{reply.send(&[b"pong"])};
+				Ok(())
+			})
+			.add("add", |args, reply| {
+				let terms: Vec<i64> = try!{args.rest()};
+				let sum: i64 = terms.iter().sum();
+				total += sum;
+				try!{reply.send_with(|message| message.write(&sum))};
+				Ok(())
+			})
+			.add("div", |args, reply| {
+				let a: i64 = try!{args.argument()};
+				let b: i64 = try!{args.argument()};
+				try!{args.expect_end()};
+				if b == 0 {
+					return Err(RemoteError::new("division-by-zero", "Division by zero").into());
+				}
+				try!{reply.send_with(|message| message.write(&(a / b)))};
+				Ok(())
+			})
+			.add("max", |args, reply| {
+				let a: i64 = try!{args.argument()};
+				let b: Option<i64> = try!{args.optional()};
+				try!{reply.send_with(|message| message.write(&b.map_or(a, |b| a.max(b))))};
+				Ok(())
+			});
+		match threaded {
+			true => router.serve_threaded(parser, &mut generator).unwrap(),
+			false => router.serve(&mut parser, &mut generator).unwrap(),
+		}
+		drop(router);
+		assert_eq!(6, total);
+	}
+}
+
+#[test]
+fn it_dispatches_requests_by_verb() {
+	for &threaded in &[false, true] {
+		let (mut client, server) = connect(serve_calculator(threaded));
+
+		assert_eq!(message("pong"), client.call(&[b"ping"]).unwrap());
+		let a = client.send(&[b"add", b"1", b"2"]).unwrap();
+		let b = client.send(&[b"add", b"3"]).unwrap();
+		let c = client.send(&[b"div", b"7", b"2"]).unwrap();
+		assert_eq!(message("3"), client.receive(a).unwrap());
+		assert_eq!(message("3"), client.receive(b).unwrap());
+		assert_eq!(message("3"), client.receive(c).unwrap());
+		assert_eq!(message("4"), client.call(&[b"max", b"4"]).unwrap());
+		assert_eq!(message("4"), client.call(&[b"max", b"4", b""]).unwrap());
+		assert_eq!(message("5"), client.call(&[b"max", b"4", b"5"]).unwrap());
+
+		drop(client);
+		server.join().unwrap();
+	}
+}
+
+#[test]
+fn it_replies_with_errors() {
+	for &threaded in &[false, true] {
+		let (mut client, server) = connect(serve_calculator(threaded));
+
+		assert_eq!(RemoteError::new("unknown-verb", "Unknown verb"), remote_error(client.call(&[b"mul", b"2", b"3"])));
+		assert_eq!(RemoteError::new("division-by-zero", "Division by zero"), remote_error(client.call(&[b"div", b"1", b"0"])));
+		assert_eq!("bad-request", remote_error(client.call(&[b"div", b"1"])).code);
+		assert_eq!("bad-request", remote_error(client.call(&[b"div", b"1", b"2", b"3"])).code);
+		assert_eq!("bad-request", remote_error(client.call(&[b"add", b"x"])).code);
+		assert_eq!(message("pong"), client.call(&[b"ping", b"extra"]).unwrap());
+		assert_eq!("missing-verb", remote_error(client.call(&[])).code);
+		assert_eq!(message("6"), client.call(&[b"add", b"6"]).unwrap());
+
+		drop(client);
+		server.join().unwrap();
+	}
+}
+
+fn serve_negotiated(before_negotiation: BeforeNegotiation)
+	-> impl FnOnce(PullParser<PipeReader>, PushGenerator<PipeWriter>) + Send + 'static
+{
+	move |mut parser, mut generator| {
+		let protocol = accept(&mut parser, &mut generator, &["lol/1", "lol/2"], before_negotiation);
+		let protocol = match protocol {
+			Ok(protocol) => protocol,
+			Err(_) => return,
+		};
+		let mut router = Router::new();
+		router.add("which", |_args, reply| {
+			try!{reply.send(&[protocol.as_bytes()])};
+			Ok(())
+		});
+		router.serve(&mut parser, &mut generator).unwrap();
+	}
+}
+
+#[test]
+fn it_negotiates_a_protocol() {
+	let (mut client, server) = connect(serve_negotiated(BeforeNegotiation::Reject));
+
+	assert_eq!("not-negotiated", remote_error(client.call(&[b"which"])).code);
+	assert_eq!("unsupported-protocol", remote_error(client.negotiate(&["lol/3"])).code);
+	assert_eq!("lol/2", client.negotiate(&["lol/3", "lol/2", "lol/1"]).unwrap());
+	assert_eq!(message("lol/2"), client.call(&[b"which"]).unwrap());
+
+	drop(client);
+	server.join().unwrap();
+}
+
+#[test]
+fn it_can_ignore_or_fail_on_requests_before_negotiation() {
+	let (mut client, server) = connect(serve_negotiated(BeforeNegotiation::Ignore));
+	client.send(&[b"which"]).unwrap();
+	assert_eq!("lol/1", client.negotiate(&["lol/1"]).unwrap());
+	assert_eq!(1, client.pending());
+	assert_eq!(message("lol/1"), client.call(&[b"which"]).unwrap());
+	drop(client);
+	server.join().unwrap();
+
+	let (mut client, server) = connect(serve_negotiated(BeforeNegotiation::Fail));
+	let failed = client.send(&[b"which"]).unwrap();
+	server.join().unwrap();
+	match client.receive(failed) {
+		Err(Error::Closed) => (),
+		x => panic!("Unexpected {:?}", x),
+	}
+}
+
+#[test]
+fn it_discards_notifications_before_negotiation() {
+	for &before_negotiation in &[BeforeNegotiation::Reject, BeforeNegotiation::Fail] {
+		let mut parser = PullParser::new(&b"* hello\n* protocol lol/1\n0 protocol lol/1\n"[..]);
+		let mut buffer = Vec::new();
+		{
+			let mut generator = PushGenerator::new(&mut buffer);
+			assert_eq!("lol/1", accept(&mut parser, &mut generator, &["lol/1"], before_negotiation).unwrap());
+		}
+		assert_eq!(&b"0 protocol lol/1\n"[..], &buffer[..]);
+	}
+}
+
+#[test]
+fn it_writes_error_replies_from_any_error() {
+	let mut buffer = Vec::new();
+	{
+		let mut generator = PushGenerator::new(&mut buffer);
+		generator.write_error(b"0", "not-found", "No such key").unwrap();
+		generator.write_error_from(b"1", &RemoteError::new("busy", "")).unwrap();
+		generator.write_error_from(b"2", &::pullparser::Error::Unspecified("Missing argument")).unwrap();
+		let err = ::std::io::Error::new(::std::io::ErrorKind::Other, "disk on fire");
+		generator.write_error_from(b"3", &err).unwrap();
+	}
+	assert_eq!(
+		&b"0 error not-found {11}No such key\n1 error busy {0}\n2 error bad-request {19}Unspecified error: {16}Missing argument\n3 error failed {12}disk on fire\n"[..],
+		&buffer[..]
+	);
+
+	let reply: OwnedMessage = "error busy".parse().unwrap();
+	assert_eq!(Some(RemoteError::new("busy", "")), RemoteError::from_reply(&reply));
+	assert_eq!(None, RemoteError::from_reply(&message("ok")));
+}
+
+fn serve_subscriptions(mut parser: PullParser<PipeReader>, mut generator: PushGenerator<PipeWriter>) {
+	let mut router = Router::new();
+	router.add("subscribe", |args, reply| {
+		let topic: String = try!{args.argument()};
+		try!{reply.send(&[b"ok"])};
+		try!{reply.notify(&[topic.as_bytes(), b"1"])};
+		try!{reply.notify(&[topic.as_bytes(), b"2"])};
+		Ok(())
+	});
+	router.add("echo", |args, reply| {
+		let text: String = try!{args.argument()};
+		try!{reply.notify(&[b"echoing"])};
+		try!{reply.send(&[text.as_bytes()])};
+		Ok(())
+	});
+	router.serve(&mut parser, &mut generator).unwrap();
+}
+
+#[test]
+fn it_queues_notifications() {
+	let (mut client, server) = connect(serve_subscriptions);
+
+	assert_eq!(message("ok"), client.call(&[b"subscribe", b"news"]).unwrap());
+	assert_eq!(message("news 1"), client.receive_notification().unwrap());
+	let id = client.send(&[b"echo", b"a"]).unwrap();
+	assert_eq!(message("news 2"), client.receive_notification().unwrap());
+	assert_eq!(message("echoing"), client.receive_notification().unwrap());
+	assert_eq!(message("a"), client.receive(id).unwrap());
+
+	drop(client);
+	server.join().unwrap();
+}
+
+#[test]
+fn it_passes_notifications_to_a_handler() {
+	let (mut client, server) = connect(serve_subscriptions);
+	let (sender, receiver) = ::std::sync::mpsc::channel();
+
+	assert_eq!(message("ok"), client.call(&[b"subscribe", b"news"]).unwrap());
+	assert_eq!(message("news 1"), client.receive_notification().unwrap());
+	assert_eq!(message("a"), client.call(&[b"echo", b"a"]).unwrap());
+	client.set_notification_handler(move |notification| sender.send(notification).unwrap());
+	assert!(client.receive_notification().is_err());
+	assert_eq!(message("b"), client.call(&[b"echo", b"b"]).unwrap());
+
+	let notifications: Vec<OwnedMessage> = receiver.try_iter().collect();
+	assert_eq!(vec![message("news 2"), message("echoing"), message("echoing")], notifications);
+
+	let mut buffer = Vec::new();
+	PushGenerator::new(&mut buffer).write_notification(&[b"a b"]).unwrap();
+	assert_eq!(&b"* {3}a b\n"[..], &buffer[..]);
+
+	drop(client);
+	server.join().unwrap();
+}
+
+fn serve_streams(mut parser: PullParser<PipeReader>, mut generator: PushGenerator<PipeWriter>) {
+	let mut router = Router::new();
+	router.add("count", |args, reply| {
+		let n: u32 = try!{args.argument()};
+		try!{reply.start_stream()};
+		for i in 0..n {
+			try!{reply.send_item_with(|message| message.write(&i))};
+		}
+		Ok(())
+	});
+	router.add("countdown", |args, reply| {
+		let n: u32 = try!{args.argument()};
+		try!{reply.send_item_with(|message| message.write(&n))};
+		try!{reply.send_item_with(|message| message.write(&(n - 1)))};
+		Err(RemoteError::new("liftoff", "Out of numbers").into())
+	});
+	router.add("ping", |_args, reply| {
+		try!{reply.send(&[b"pong"])};
+		assert!(reply.send_item(&[b"pong"]).is_err());
+		Ok(())
+	});
+	router.serve(&mut parser, &mut generator).unwrap();
+}
+
+#[test]
+fn it_streams_replies() {
+	let (mut client, server) = connect(serve_streams);
+
+	let items: Result<Vec<_>, _> = client.call_stream(&[b"count", b"3"]).unwrap().collect();
+	assert_eq!(vec![message("0"), message("1"), message("2")], items.unwrap());
+	assert_eq!(0, client.pending());
+
+	assert_eq!(0, client.call_stream(&[b"count", b"0"]).unwrap().count());
+
+	let a = client.send_stream(&[b"count", b"2"]).unwrap();
+	let b = client.send(&[b"ping"]).unwrap();
+	assert_eq!(message("pong"), client.receive(b).unwrap());
+	let items: Result<Vec<_>, _> = client.stream(a).collect();
+	assert_eq!(vec![message("0"), message("1")], items.unwrap());
+
+	drop(client);
+	server.join().unwrap();
+}
+
+#[test]
+fn it_ends_streams_with_trailing_errors() {
+	let (mut client, server) = connect(serve_streams);
+
+	let mut stream = client.call_stream(&[b"countdown", b"2"]).unwrap();
+	assert_eq!(message("2"), stream.next().unwrap().unwrap());
+	assert_eq!(message("1"), stream.next().unwrap().unwrap());
+	assert_eq!("liftoff", remote_error(stream.next().unwrap()).code);
+	assert!(stream.next().is_none());
+
+	let mut stream = client.call_stream(&[b"count"]).unwrap();
+	assert_eq!("bad-request", remote_error(stream.next().unwrap()).code);
+	assert!(stream.next().is_none());
+
+	let mut stream = client.call_stream(&[b"ping"]).unwrap();
+	assert!(stream.next().unwrap().is_err());
+	assert!(stream.next().is_none());
+	assert_eq!(0, client.pending());
+
+	drop(client);
+	server.join().unwrap();
+}
+
+// Serve requests that run until they are cancelled, and report the
+// cancelled requests on `cancelled`
+fn serve_cancellable<R, W>(parser: PullParser<R>, generator: &mut PushGenerator<W>, cancelled: ::std::sync::mpsc::Sender<String>)
+	where R: ::std::io::Read + Send + 'static, W: ::std::io::Write
+{
+	let mut router = Router::new();
+	router.add("wait", |args, reply| {
+		let name: String = try!{args.argument()};
+		while !reply.cancellation().is_cancelled() {
+			thread::sleep(Duration::from_millis(1));
+		}
+		cancelled.send(name).unwrap();
+		try!{reply.cancellation().check()};
+		Ok(())
+	});
+	router.add("count", |_args, reply| {
+		let mut i = 0u32;
+		while !reply.cancellation().is_cancelled() {
+			try!{reply.send_item_with(|message| message.write(&i))};
+			i += 1;
+		}
+		cancelled.send(i.to_string()).unwrap();
+		Ok(())
+	});
+	router.add("ping", |_args, reply| {
+		try!{reply.send(&[b"pong"])};
+		Ok(())
+	});
+	router.serve_threaded(parser, generator).unwrap();
+}
+
+// Fails writing once the flag is set
+struct Breakable(::std::sync::Arc<::std::sync::atomic::AtomicBool>);
+
+impl io::Write for Breakable {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		match self.0.load(::std::sync::atomic::Ordering::SeqCst) {
+			true => Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken")),
+			false => Ok(buf.len()),
+		}
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+#[test]
+fn it_stops_serving_threaded_when_replying_fails() {
+	let (server_reader, mut client_writer) = io::pipe().unwrap();
+	client_writer.write_all(b"0 ping\n").unwrap();
+	let (sender, receiver) = ::std::sync::mpsc::channel();
+	thread::spawn(move || {
+		let mut router = Router::new();
+		router.add("ping", |_args, reply| {
+			try!{reply.send(&[b"pong"])};
+			Ok(())
+		});
+		let mut generator = PushGenerator::new(Breakable(::std::sync::Arc::new(::std::sync::atomic::AtomicBool::new(true))));
+		sender.send(router.serve_threaded(PullParser::new(server_reader), &mut generator).is_err()).unwrap();
+	});
+
+	// The connection is still open, so the reader thread is blocked on it
+	assert!(receiver.recv_timeout(Duration::from_secs(10)).unwrap());
+	drop(client_writer);
+}
+
+#[test]
+fn it_cancels_requests() {
+	let (sender, receiver) = ::std::sync::mpsc::channel();
+	let (mut client, server) = connect(move |parser, mut generator| {
+		serve_cancellable(parser, &mut generator, sender)
+	});
+
+	let a = client.send(&[b"wait", b"a"]).unwrap();
+	let b = client.send_stream(&[b"count"]).unwrap();
+	client.cancel(a).unwrap();
+	assert_eq!("a", receiver.recv().unwrap());
+	let items: Vec<OwnedMessage> = client.stream(b).take(3).map(Result::unwrap).collect();
+	assert_eq!(vec![message("0"), message("1"), message("2")], items);
+	client.cancel(b).unwrap();
+	assert!(receiver.recv().unwrap().parse::<u32>().unwrap() >= 3);
+	assert!(client.cancel(b).is_err());
+	assert_eq!(0, client.pending());
+
+	assert_eq!(message("pong"), client.call(&[b"ping"]).unwrap());
+
+	drop(client);
+	server.join().unwrap();
+}
+
+#[test]
+fn it_times_out_requests() {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let (sender, receiver) = ::std::sync::mpsc::channel();
+	let server = thread::spawn(move || {
+		let stream = listener.accept().unwrap().0;
+		let parser = PullParser::new(stream.try_clone().unwrap());
+		let mut generator = PushGenerator::new(stream);
+		serve_cancellable(parser, &mut generator, sender);
+	});
+
+	let stream = TcpStream::connect(address).unwrap();
+	stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+	let mut client = Client::new(stream.try_clone().unwrap(), stream);
+
+	let started = Instant::now();
+	let a = client.send(&[b"wait", b"a"]).unwrap();
+	match client.call_with_timeout(&[b"wait", b"b"], Duration::from_millis(50)) {
+		Err(Error::TimedOut(_)) => (),
+		x => panic!("Unexpected {:?}", x),
+	}
+	assert!(started.elapsed() >= Duration::from_millis(50));
+	assert_eq!(1, client.pending());
+
+	client.set_deadline(a, Instant::now()).unwrap();
+	match client.receive_any() {
+		Err(Error::TimedOut(id)) => assert_eq!(a, id),
+		x => panic!("Unexpected {:?}", x),
+	}
+	assert_eq!(0, client.pending());
+	assert_eq!(message("pong"), client.call(&[b"ping"]).unwrap());
+
+	let mut cancelled: Vec<String> = receiver.iter().take(2).collect();
+	cancelled.sort();
+	assert_eq!(vec!["a", "b"], cancelled);
+
+	drop(client);
+	server.join().unwrap();
+}
+
+#[test]
+fn it_times_out_requests_after_empty_lines() {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let server = thread::spawn(move || {
+		let mut stream = listener.accept().unwrap().0;
+		PullParser::new(stream.try_clone().unwrap()).read_owned_message().unwrap();
+		stream.write_all(b"\n\r\n").unwrap();
+		// Keep the connection open until the client closes it
+		let mut rest = Vec::new();
+		stream.read_to_end(&mut rest).unwrap();
+	});
+
+	let stream = TcpStream::connect(address).unwrap();
+	stream.set_read_timeout(Some(Duration::from_millis(5))).unwrap();
+	let mut client = Client::new(stream.try_clone().unwrap(), stream);
+	match client.call_with_timeout(&[b"wait"], Duration::from_millis(100)) {
+		Err(Error::TimedOut(_)) => (),
+		x => panic!("Unexpected {:?}", x),
+	}
+
+	drop(client);
+	server.join().unwrap();
+}
+
+#[test]
+fn it_takes_requests_from_many_threads() {
+	let (client_reader, server_writer) = io::pipe().unwrap();
+	let (server_reader, client_writer) = io::pipe().unwrap();
+	let server = thread::spawn(move || {
+		serve_subscriptions(PullParser::new(server_reader), PushGenerator::new(server_writer))
+	});
+	let client = ThreadedClient::new(client_reader, client_writer);
+
+	let threads: Vec<_> = (0..8).map(|i| {
+		let client = client.clone();
+		thread::spawn(move || {
+			for j in 0..20 {
+				let text = format!("{} {}", i, j);
+				assert_eq!(message(&format!("{{{}}}{}", text.len(), text)), client.call(&[b"echo", text.as_bytes()]).unwrap());
+			}
+		})
+	}).collect();
+	for thread in threads {
+		thread.join().unwrap();
+	}
+	assert_eq!(0, client.pending());
+
+	let (sender, receiver) = ::std::sync::mpsc::channel();
+	client.set_notification_handler(move |notification| sender.send(notification).unwrap());
+	assert_eq!(message("ok"), client.call(&[b"subscribe", b"news"]).unwrap());
+	assert!(receiver.iter().take(160).all(|notification| notification == message("echoing")));
+	assert_eq!(message("news 1"), receiver.recv().unwrap());
+	assert_eq!(message("news 2"), receiver.recv().unwrap());
+
+	drop(client);
+	server.join().unwrap();
+}
+
+#[test]
+fn it_times_out_and_cancels_requests_from_a_threaded_client() {
+	let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+	let address = listener.local_addr().unwrap();
+	let (sender, receiver) = ::std::sync::mpsc::channel();
+	let server = thread::spawn(move || {
+		let stream = listener.accept().unwrap().0;
+		let parser = PullParser::new(stream.try_clone().unwrap());
+		let mut generator = PushGenerator::new(stream);
+		serve_cancellable(parser, &mut generator, sender);
+	});
+	let client = ThreadedClient::from_tcp(TcpStream::connect(address).unwrap()).unwrap();
+
+	match client.call_with_timeout(&[b"wait", b"a"], Duration::from_millis(20)) {
+		Err(Error::TimedOut(_)) => (),
+		x => panic!("Unexpected {:?}", x),
+	}
+	assert_eq!("a", receiver.recv().unwrap());
+
+	let mut stream = client.send_stream(&[b"count"]).unwrap();
+	let items: Vec<OwnedMessage> = stream.by_ref().take(3).map(Result::unwrap).collect();
+	assert_eq!(vec![message("0"), message("1"), message("2")], items);
+	stream.cancel().unwrap();
+	assert!(receiver.recv().unwrap().parse::<u32>().unwrap() >= 3);
+
+	let ping = client.send(&[b"ping"]).unwrap();
+	assert_eq!(message("pong"), ping.receive_timeout(Duration::from_secs(10)).unwrap());
+	assert_eq!(0, client.pending());
+
+	// Dropping the client closes the connection
+	drop(client);
+	server.join().unwrap();
+}
+
+#[test]
+fn it_fails_threaded_requests_when_the_connection_closes() {
+	let (client_reader, server_writer) = io::pipe().unwrap();
+	let (server_reader, client_writer) = io::pipe().unwrap();
+	let client = ThreadedClient::new(client_reader, client_writer);
+	let pending = client.send(&[b"ping"]).unwrap();
+	drop(server_writer);
+	match pending.receive() {
+		Err(Error::Closed) => (),
+		x => panic!("Unexpected {:?}", x),
+	}
+	match client.send(&[b"ping"]) {
+		Err(Error::Closed) => (),
+		x => panic!("Unexpected {:?}", x.map(|pending| pending.id())),
+	}
+	drop(server_reader);
+}
+
+// Reads `data` once it is let through, so that the test decides when the
+// reader thread of a `ThreadedClient` sees it
+struct GatedReader {
+	gate: Option<::std::sync::mpsc::Receiver<()>>,
+	data: io::Cursor<Vec<u8>>,
+}
+
+impl GatedReader {
+	fn new(data: &[u8]) -> (::std::sync::mpsc::Sender<()>, GatedReader) {
+		let (sender, receiver) = ::std::sync::mpsc::channel();
+		(sender, GatedReader { gate: Some(receiver), data: io::Cursor::new(data.to_vec()) })
+	}
+}
+
+impl io::Read for GatedReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		if let Some(gate) = self.gate.take() {
+			let _ = gate.recv();
+		}
+		self.data.read(buf)
+	}
+}
+
+#[test]
+fn it_queues_a_limited_number_of_notifications_for_a_threaded_client() {
+	let mut data = Vec::new();
+	for i in 0..1030 {
+		data.extend(format!("* news {}\n", i).bytes());
+	}
+	let (gate, reader) = GatedReader::new(&data);
+	let client = ThreadedClient::new(reader, io::sink());
+	let pending = client.send(&[b"ping"]).unwrap();
+	gate.send(()).unwrap();
+
+	// Wait until everything has been read
+	match pending.receive() {
+		Err(Error::Closed) => (),
+		x => panic!("Unexpected {:?}", x),
+	}
+	for i in 6..1030 {
+		assert_eq!(message(&format!("news {}", i)), client.receive_notification().unwrap());
+	}
+	match client.receive_notification() {
+		Err(Error::Closed) => (),
+		x => panic!("Unexpected {:?}", x),
+	}
+}
+
+#[test]
+fn it_lets_notification_handlers_replace_themselves() {
+	let (gate, reader) = GatedReader::new(b"* a\n* b\n* c\n");
+	let client = ThreadedClient::new(reader, io::sink());
+	let (sender, receiver) = ::std::sync::mpsc::channel();
+	{
+		let client = client.clone();
+		client.clone().set_notification_handler(move |notification| {
+			sender.send(("first", notification)).unwrap();
+			let sender = sender.clone();
+			client.set_notification_handler(move |notification| sender.send(("second", notification)).unwrap());
+		});
+	}
+	assert!(client.receive_notification().is_err());
+	gate.send(()).unwrap();
+
+	let received: Vec<_> = receiver.iter().take(3).collect();
+	assert_eq!(vec![("first", message("a")), ("second", message("b")), ("second", message("c"))], received);
+}
+
+#[test]
+fn it_reports_failing_to_cancel_a_request_that_timed_out() {
+	let (gate, reader) = GatedReader::new(b"");
+	let broken = ::std::sync::Arc::new(::std::sync::atomic::AtomicBool::new(false));
+	let client = ThreadedClient::new(reader, Breakable(broken.clone()));
+	let pending = client.send(&[b"wait"]).unwrap();
+	broken.store(true, ::std::sync::atomic::Ordering::SeqCst);
+	match pending.receive_timeout(Duration::from_millis(10)) {
+		Err(Error::Generate(_)) => (),
+		x => panic!("Unexpected {:?}", x),
+	}
+	drop(gate);
+}
@@ -0,0 +1,418 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::str;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use command::ToMessage;
+use message::OwnedMessage;
+use pullparser::PullParser;
+use pushgenerator::{self, PushGenerator};
+use super::client::{check_error, stream_item};
+use super::error::*;
+
+type NotificationHandler = Arc<Mutex<Box<FnMut(OwnedMessage) + Send>>>;
+
+// The number of notifications that are kept for `receive_notification`
+const MAX_QUEUED_NOTIFICATIONS: usize = 1024;
+
+/// A client for the same conventions as `Client`, which can be used from
+/// many threads at once. It is a handle that can be cloned and passed to
+/// other threads.
+///
+/// A reader thread reads the replies and passes each one to the thread
+/// waiting for it, through a `PendingRequest`. Requests are written whole,
+/// so requests from different threads do not interleave. Replies with
+/// unknown ids are discarded: unlike with `Client`, no caller is reading on
+/// the reader thread to report them to, and late replies to cancelled or
+/// timed out requests are expected to arrive there.
+///
+/// Notifications are passed to the notification handler, which runs on the
+/// reader thread. Until one is set, they are queued for
+/// `receive_notification`. At most 1024 notifications are queued, after which
+/// the oldest are discarded.
+///
+/// The reader thread runs until the connection is closed. For clients made
+/// with `from_tcp` or `from_unix`, the connection is shut down when the last
+/// handle is dropped.
+pub struct ThreadedClient<W: Write> {
+	inner: Arc<Inner<W>>,
+}
+
+struct Inner<W: Write> {
+	generator: Mutex<PushGenerator<W>>,
+	state: Arc<Mutex<State>>,
+	notifications: Arc<(Mutex<Notifications>, Condvar)>,
+	shutdown: Option<Box<Fn() + Send + Sync>>,
+}
+
+struct State {
+	next_id: u64,
+	pending: HashMap<u64, mpsc::Sender<Result<OwnedMessage, Error>>>,
+	streams: HashSet<u64>,
+	closed: bool,
+}
+
+struct Notifications {
+	queue: VecDeque<OwnedMessage>,
+	handler: Option<NotificationHandler>,
+	closed: bool,
+}
+
+impl<W: Write> Clone for ThreadedClient<W> {
+	fn clone(&self) -> ThreadedClient<W> {
+		ThreadedClient { inner: self.inner.clone() }
+	}
+}
+
+impl<W: Write> Drop for Inner<W> {
+	fn drop(&mut self) {
+		if let Some(ref shutdown) = self.shutdown {
+			shutdown();
+		}
+	}
+}
+
+impl ThreadedClient<TcpStream> {
+	pub fn from_tcp(stream: TcpStream) -> io::Result<ThreadedClient<TcpStream>> {
+		let reader = try!{stream.try_clone()};
+		let shutdown = try!{stream.try_clone()};
+		Ok(ThreadedClient::start(reader, stream, Some(Box::new(move || {
+			let _ = shutdown.shutdown(Shutdown::Both);
+		}))))
+	}
+}
+
+#[cfg(unix)]
+impl ThreadedClient<UnixStream> {
+	pub fn from_unix(stream: UnixStream) -> io::Result<ThreadedClient<UnixStream>> {
+		let reader = try!{stream.try_clone()};
+		let shutdown = try!{stream.try_clone()};
+		Ok(ThreadedClient::start(reader, stream, Some(Box::new(move || {
+			let _ = shutdown.shutdown(Shutdown::Both);
+		}))))
+	}
+}
+
+impl<W: Write> ThreadedClient<W> {
+	/// Start a client reading replies from `reader` on a new thread, and
+	/// writing requests to `writer`. The reader is buffered by the client.
+	pub fn new<R: Read + Send + 'static>(reader: R, writer: W) -> ThreadedClient<W> {
+		ThreadedClient::start(reader, writer, None)
+	}
+
+	fn start<R: Read + Send + 'static>(reader: R, writer: W, shutdown: Option<Box<Fn() + Send + Sync>>) -> ThreadedClient<W> {
+		let state = Arc::new(Mutex::new(State {
+			next_id: 0,
+			pending: HashMap::new(),
+			streams: HashSet::new(),
+			closed: false,
+		}));
+		let notifications = Arc::new((Mutex::new(Notifications {
+			queue: VecDeque::new(),
+			handler: None,
+			closed: false,
+		}), Condvar::new()));
+		{
+			let state = state.clone();
+			let notifications = notifications.clone();
+			thread::spawn(move || read_replies(PullParser::new(BufReader::new(reader)), &state, &notifications));
+		}
+		ThreadedClient {
+			inner: Arc::new(Inner {
+				generator: Mutex::new(PushGenerator::new(writer)),
+				state: state,
+				notifications: notifications,
+				shutdown: shutdown,
+			}),
+		}
+	}
+
+	/// Pass notifications to `handler` as they are read, on the reader
+	/// thread. Any queued notifications are passed to it right away. The
+	/// handler may replace itself by calling this.
+	pub fn set_notification_handler<F: FnMut(OwnedMessage) + Send + 'static>(&self, handler: F) {
+		let handler: NotificationHandler = Arc::new(Mutex::new(Box::new(handler)));
+		// Hold the new handler while passing the queued notifications to it,
+		// so that the reader thread can not pass it newer ones before them
+		let mut locked_handler = handler.lock().unwrap();
+		let queued = {
+			let mut notifications = self.inner.notifications.0.lock().unwrap();
+			notifications.handler = Some(handler.clone());
+			notifications.queue.drain(..).collect::<Vec<_>>()
+		};
+		// Threads waiting in receive_notification fail now
+		self.inner.notifications.1.notify_all();
+		for notification in queued {
+			(*locked_handler)(notification);
+		}
+	}
+
+	/// Wait for the next notification. Fails if a notification handler is
+	/// set, or when the connection is closed and no notifications are left.
+	pub fn receive_notification(&self) -> Result<OwnedMessage, Error> {
+		let queued = &self.inner.notifications.1;
+		let mut notifications = self.inner.notifications.0.lock().unwrap();
+		loop {
+			if notifications.handler.is_some() {
+				return Err(Error::Unspecified("Notifications are passed to the notification handler"));
+			}
+			if let Some(notification) = notifications.queue.pop_front() {
+				return Ok(notification);
+			}
+			if notifications.closed {
+				return Err(Error::Closed);
+			}
+			notifications = queued.wait(notifications).unwrap();
+		}
+	}
+
+	/// Send a request with the fields written by `write_fields` following a
+	/// new id. Nothing is sent if `write_fields` fails.
+	pub fn send_with<F>(&self, write_fields: F) -> Result<PendingRequest<W>, Error>
+		where F: FnOnce(&mut pushgenerator::Message<W>) -> Result<(), pushgenerator::Error>
+	{
+		self.start_request(false, write_fields)
+	}
+
+	pub fn send(&self, fields: &[&[u8]]) -> Result<PendingRequest<W>, Error> {
+		self.send_with(|message| write_all(message, fields))
+	}
+
+	pub fn send_command<C: ToMessage>(&self, command: &C) -> Result<PendingRequest<W>, Error> {
+		self.send_with(|message| command.write_fields(message))
+	}
+
+	/// Send a request that is answered with a stream of replies, which are
+	/// read by iterating over the `PendingRequest`.
+	pub fn send_stream(&self, fields: &[&[u8]]) -> Result<PendingRequest<W>, Error> {
+		self.start_request(true, |message| write_all(message, fields))
+	}
+
+	/// Send a request and wait for its reply.
+	pub fn call(&self, fields: &[&[u8]]) -> Result<OwnedMessage, Error> {
+		try!{self.send(fields)}.receive()
+	}
+
+	/// Send a request and wait for its reply, cancelling the request if the
+	/// reply has not arrived within `timeout`.
+	pub fn call_with_timeout(&self, fields: &[&[u8]], timeout: Duration) -> Result<OwnedMessage, Error> {
+		try!{self.send(fields)}.receive_timeout(timeout)
+	}
+
+	/// Cancel the pending request with the given id by sending
+	/// `* cancel <id>`. Replies to it are discarded.
+	pub fn cancel(&self, id: u64) -> Result<(), Error> {
+		match try!{self.cancel_pending(id)} {
+			true => Ok(()),
+			false => Err(Error::Unspecified("No request with this id is pending")),
+		}
+	}
+
+	// Cancel the request if it is pending. Returns whether it was.
+	fn cancel_pending(&self, id: u64) -> Result<bool, Error> {
+		{
+			let mut state = self.inner.state.lock().unwrap();
+			if state.pending.remove(&id).is_none() {
+				return Ok(false);
+			}
+			state.streams.remove(&id);
+		}
+		let mut generator = self.inner.generator.lock().unwrap();
+		try!{generator.write_notification(&[b"cancel", id.to_string().as_bytes()])};
+		try!{generator.flush()};
+		Ok(true)
+	}
+
+	/// The number of requests that have been sent, but whose replies have
+	/// not yet been received.
+	pub fn pending(&self) -> usize {
+		self.inner.state.lock().unwrap().pending.len()
+	}
+
+	fn start_request<F>(&self, stream: bool, write_fields: F) -> Result<PendingRequest<W>, Error>
+		where F: FnOnce(&mut pushgenerator::Message<W>) -> Result<(), pushgenerator::Error>
+	{
+		// Register the request before sending it, so the reader thread
+		// knows about it when the reply arrives
+		let (sender, receiver) = mpsc::channel();
+		let id = {
+			let mut state = self.inner.state.lock().unwrap();
+			if state.closed {
+				return Err(Error::Closed);
+			}
+			let id = state.next_id;
+			state.next_id += 1;
+			state.pending.insert(id, sender);
+			if stream {
+				state.streams.insert(id);
+			}
+			id
+		};
+		if let Err(err) = self.write_request(id, write_fields) {
+			let mut state = self.inner.state.lock().unwrap();
+			state.pending.remove(&id);
+			state.streams.remove(&id);
+			return Err(err);
+		}
+		Ok(PendingRequest {
+			client: self.clone(),
+			id: id,
+			receiver: receiver,
+			done: false,
+		})
+	}
+
+	fn write_request<F>(&self, id: u64, write_fields: F) -> Result<(), Error>
+		where F: FnOnce(&mut pushgenerator::Message<W>) -> Result<(), pushgenerator::Error>
+	{
+		let mut generator = self.inner.generator.lock().unwrap();
+		{
+			let mut message = try!{generator.next_buffered_message()};
+			try!{message.write(&id)};
+			if let Err(err) = write_fields(&mut message) {
+				try!{message.abort()};
+				return Err(Error::Generate(err));
+			}
+			try!{message.commit()};
+		}
+		try!{generator.flush()};
+		Ok(())
+	}
+}
+
+/// A request sent with a `ThreadedClient`, for waiting for its reply. For
+/// requests sent with `send_stream`, iterate over it to read the stream of
+/// replies, as with `ReplyStream`.
+pub struct PendingRequest<W: Write> {
+	client: ThreadedClient<W>,
+	id: u64,
+	receiver: mpsc::Receiver<Result<OwnedMessage, Error>>,
+	done: bool,
+}
+
+impl<W: Write> PendingRequest<W> {
+	pub fn id(&self) -> u64 {
+		self.id
+	}
+
+	/// Wait for the reply, and return the fields following the id.
+	pub fn receive(self) -> Result<OwnedMessage, Error> {
+		match self.receiver.recv() {
+			Ok(reply) => check_error(try!{reply}).map_err(Error::Remote),
+			Err(_) => Err(Error::Closed),
+		}
+	}
+
+	/// Wait for the reply, cancelling the request if it has not arrived
+	/// within `timeout`.
+	pub fn receive_timeout(self, timeout: Duration) -> Result<OwnedMessage, Error> {
+		match self.receiver.recv_timeout(timeout) {
+			Ok(reply) => check_error(try!{reply}).map_err(Error::Remote),
+			Err(mpsc::RecvTimeoutError::Timeout) => match try!{self.client.cancel_pending(self.id)} {
+				true => Err(Error::TimedOut(self.id)),
+				// The reply arrived after all
+				false => self.receive(),
+			},
+			Err(mpsc::RecvTimeoutError::Disconnected) => Err(Error::Closed),
+		}
+	}
+
+	/// Cancel the request, as with `ThreadedClient::cancel`.
+	pub fn cancel(self) -> Result<(), Error> {
+		self.client.cancel(self.id)
+	}
+}
+
+impl<W: Write> Iterator for PendingRequest<W> {
+	type Item = Result<OwnedMessage, Error>;
+
+	fn next(&mut self) -> Option<Result<OwnedMessage, Error>> {
+		if self.done {
+			return None;
+		}
+		match self.receiver.recv() {
+			Ok(Ok(reply)) => stream_item(reply, &mut self.done),
+			Ok(Err(err)) => {
+				self.done = true;
+				Some(Err(err))
+			},
+			Err(_) => {
+				self.done = true;
+				Some(Err(Error::Closed))
+			},
+		}
+	}
+}
+
+fn write_all<W: Write>(message: &mut pushgenerator::Message<W>, fields: &[&[u8]]) -> Result<(), pushgenerator::Error> {
+	for field in fields {
+		try!{message.write_field(field)};
+	}
+	Ok(())
+}
+
+// The reader thread. When the connection is closed, or reading fails, the
+// pending requests fail with `Error::Closed`.
+fn read_replies<R: Read>(mut parser: PullParser<R>, state: &Mutex<State>, notifications: &(Mutex<Notifications>, Condvar)) {
+	while let Ok(Some(message)) = parser.read_owned_message() {
+		let fields: OwnedMessage = message.iter().skip(1).collect();
+		if &message[0] == b"*" {
+			notify(notifications, fields);
+			continue;
+		}
+
+		// Ids that are not numbers can not belong to any request, and are
+		// discarded like other unknown ids
+		let id = match str::from_utf8(&message[0]).ok().and_then(|id| id.parse().ok()) {
+			Some(id) => id,
+			None => continue,
+		};
+		let mut state = state.lock().unwrap();
+		let sender = if !state.streams.contains(&id) || fields.get(0) != Some(&b"item"[..]) {
+			state.streams.remove(&id);
+			state.pending.remove(&id)
+		} else {
+			state.pending.get(&id).cloned()
+		};
+		if let Some(sender) = sender {
+			// The receiver may have been dropped without waiting
+			let _ = sender.send(Ok(fields));
+		}
+	}
+
+	let mut state = state.lock().unwrap();
+	state.closed = true;
+	state.streams.clear();
+	for (_, sender) in state.pending.drain() {
+		let _ = sender.send(Err(Error::Closed));
+	}
+	notifications.0.lock().unwrap().closed = true;
+	notifications.1.notify_all();
+}
+
+// Pass a notification to the handler, which is called without holding the
+// lock on the notifications, or queue it
+fn notify(notifications: &(Mutex<Notifications>, Condvar), notification: OwnedMessage) {
+	let queued = &notifications.1;
+	let handler = {
+		let mut notifications = notifications.0.lock().unwrap();
+		match notifications.handler {
+			Some(ref handler) => handler.clone(),
+			None => {
+				if notifications.queue.len() == MAX_QUEUED_NOTIFICATIONS {
+					notifications.queue.pop_front();
+				}
+				notifications.queue.push_back(notification);
+				queued.notify_one();
+				return;
+			},
+		}
+	};
+	let mut handler = handler.lock().unwrap();
+	(*handler)(notification);
+}
@@ -0,0 +1,972 @@
+/*!
+Serde support for PlainTalk messages, available with the `serde` feature.
+
+A value is mapped to the fields of a single message:
+
+ * Structs, tuples and tuple structs become one field per element, in
+   order. Field names are not part of the message. Nested structs and
+   tuples are flattened.
+ * Enum variants start with a field holding the variant name, followed by
+   the fields of the variant, flattened in the same way.
+ * Scalars are single fields, encoded as described in the `field` module.
+   `None` and `()` are empty fields.
+ * A sequence is mapped to the remaining fields of the message, so it is
+   only supported as the last element. Note that `Vec<u8>` is a sequence
+   of fields unless it is serialized as bytes, for example with
+   `serde_bytes`.
+ * `Option` elements at the end of a message may be left out, and are then
+   deserialized as `None`.
+
+Anything that can not be flattened into the fields of one message fails
+with an error. This includes maps, and structs, tuples, enum variants with
+data and sequences inside an `Option` or a sequence.
+*/
+
+use std::convert;
+use std::error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use serde::de::{self, DeserializeOwned, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::de::value::StringDeserializer;
+use serde::ser::{self, Impossible, Serialize};
+
+use field::{FromField, ToField};
+use message::{self, OwnedMessage};
+use pullparser::{self, PullParser};
+use pushgenerator::{self, PushGenerator};
+
+#[derive(Debug)]
+pub enum Error {
+	Parse(pullparser::Error),
+	Generate(pushgenerator::Error),
+	Unspecified(&'static str),
+	Custom(String),
+}
+
+impl fmt::Display for Error {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			Error::Parse(ref err) => write!(f, "Parse error: {}", err),
+			Error::Generate(ref err) => write!(f, "Generator error: {}", err),
+			Error::Unspecified(ref err) => write!(f, "Unspecified error: {}", err),
+			Error::Custom(ref err) => f.write_str(err),
+		}
+	}
+}
+
+impl error::Error for Error {
+	fn cause(&self) -> Option<&error::Error> {
+		match *self {
+			Error::Parse(ref err) => Some(err),
+			Error::Generate(ref err) => Some(err),
+			_ => None,
+		}
+	}
+}
+
+impl convert::From<pullparser::Error> for Error {
+	fn from(err: pullparser::Error) -> Error {
+		Error::Parse(err)
+	}
+}
+
+impl convert::From<pushgenerator::Error> for Error {
+	fn from(err: pushgenerator::Error) -> Error {
+		Error::Generate(err)
+	}
+}
+
+impl ser::Error for Error {
+	fn custom<T: fmt::Display>(msg: T) -> Error {
+		Error::Custom(msg.to_string())
+	}
+}
+
+impl de::Error for Error {
+	fn custom<T: fmt::Display>(msg: T) -> Error {
+		Error::Custom(msg.to_string())
+	}
+}
+
+const NESTED_SEQUENCE: &'static str = "Sequences can not be nested in a message";
+const SEQUENCE_NOT_LAST: &'static str = "Only the last element of a message can be a sequence";
+const NOT_FLATTENED: &'static str = "Structs, tuples and enum variants with data can not be nested in an Option or a sequence";
+const MAP: &'static str = "Maps are not supported in PlainTalk messages";
+
+/// Write `value` as the fields of `message`.
+pub fn to_message<W: Write, T: Serialize + ?Sized>(message: &mut pushgenerator::Message<W>, value: &T) -> Result<(), Error> {
+	value.serialize(&mut Serializer::new(message))
+}
+
+/// Write `value` as a message. The message is buffered, so nothing is
+/// written if serialization fails.
+pub fn to_generator<W: Write, T: Serialize + ?Sized>(generator: &mut PushGenerator<W>, value: &T) -> Result<(), Error> {
+	let mut message = try!{generator.next_buffered_message()};
+	match to_message(&mut message, value) {
+		Ok(()) => Ok(try!{message.commit()}),
+		Err(err) => {
+			try!{message.abort()};
+			Err(err)
+		},
+	}
+}
+
+pub fn to_owned_message<T: Serialize + ?Sized>(value: &T) -> Result<OwnedMessage, Error> {
+	let mut message = OwnedMessage::new();
+	try!{value.serialize(&mut Serializer::owned(&mut message))};
+	Ok(message)
+}
+
+/// Read a value from the remaining fields of `message`. Fails if there are
+/// fields left over.
+pub fn from_message<T: DeserializeOwned>(message: &mut pullparser::Message) -> Result<T, Error> {
+	let value = try!{T::deserialize(&mut Deserializer::new(message))};
+	if !message.at_end() {
+		return Err(Error::Unspecified("Unexpected trailing fields"));
+	}
+	Ok(value)
+}
+
+/// Read a value from the next message of `parser`. Returns `None` at the
+/// end of the stream.
+pub fn from_parser<R: Read, T: DeserializeOwned>(parser: &mut PullParser<R>) -> Result<Option<T>, Error> {
+	match try!{parser.read_owned_message()} {
+		Some(message) => from_owned_message(&message).map(Some),
+		None => Ok(None),
+	}
+}
+
+pub fn from_owned_message<T: DeserializeOwned>(message: &OwnedMessage) -> Result<T, Error> {
+	let mut deserializer = Deserializer::owned(message);
+	let value = try!{T::deserialize(&mut deserializer)};
+	if !deserializer.at_end() {
+		return Err(Error::Unspecified("Unexpected trailing fields"));
+	}
+	Ok(value)
+}
+
+pub struct Serializer<'m, 'a: 'm, W: 'a + Write> {
+	target: Target<'m, 'a, W>,
+	after_sequence: bool,
+}
+
+// `to_owned_message` collects the fields directly, instead of encoding them
+// and parsing them back
+enum Target<'m, 'a: 'm, W: 'a + Write> {
+	Generator(&'m mut pushgenerator::Message<'a, W>),
+	Owned(&'m mut OwnedMessage),
+}
+
+impl<'m, 'a, W: Write> Serializer<'m, 'a, W> {
+	pub fn new(message: &'m mut pushgenerator::Message<'a, W>) -> Serializer<'m, 'a, W> {
+		Serializer {
+			target: Target::Generator(message),
+			after_sequence: false,
+		}
+	}
+
+	fn field<T: ToField + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+		if self.after_sequence {
+			return Err(Error::Unspecified(SEQUENCE_NOT_LAST));
+		}
+		match self.target {
+			Target::Generator(ref mut message) => try!{message.write(value)},
+			Target::Owned(ref mut message) => message.push(value),
+		}
+		Ok(())
+	}
+
+	fn element<'s>(&'s mut self) -> FieldSerializer<'s, 'm, 'a, W> {
+		FieldSerializer { ser: self, in_sequence: false, in_option: false }
+	}
+
+	fn compound<'s>(&'s mut self, sequence: bool) -> Compound<'s, 'm, 'a, W> {
+		Compound { ser: self, sequence: sequence }
+	}
+}
+
+impl<'m> Serializer<'m, 'static, io::Sink> {
+	fn owned(message: &'m mut OwnedMessage) -> Serializer<'m, 'static, io::Sink> {
+		Serializer {
+			target: Target::Owned(message),
+			after_sequence: false,
+		}
+	}
+}
+
+macro_rules! serialize_fields {
+	($($method:ident($t:ty))*) => {
+		$(
+			fn $method(self, value: $t) -> Result<(), Error> {
+				self.field(&value)
+			}
+		)*
+	}
+}
+
+macro_rules! forward_to_element {
+	($($method:ident($t:ty))*) => {
+		$(
+			fn $method(self, value: $t) -> Result<(), Error> {
+				self.element().$method(value)
+			}
+		)*
+	}
+}
+
+impl<'s, 'm, 'a, W: Write> ser::Serializer for &'s mut Serializer<'m, 'a, W> {
+	type Ok = ();
+	type Error = Error;
+	type SerializeSeq = Compound<'s, 'm, 'a, W>;
+	type SerializeTuple = Compound<'s, 'm, 'a, W>;
+	type SerializeTupleStruct = Compound<'s, 'm, 'a, W>;
+	type SerializeTupleVariant = Compound<'s, 'm, 'a, W>;
+	type SerializeMap = Impossible<(), Error>;
+	type SerializeStruct = Compound<'s, 'm, 'a, W>;
+	type SerializeStructVariant = Compound<'s, 'm, 'a, W>;
+
+	forward_to_element! {
+		serialize_bool(bool)
+		serialize_i8(i8) serialize_i16(i16) serialize_i32(i32) serialize_i64(i64) serialize_i128(i128)
+		serialize_u8(u8) serialize_u16(u16) serialize_u32(u32) serialize_u64(u64) serialize_u128(u128)
+		serialize_f32(f32) serialize_f64(f64)
+		serialize_char(char) serialize_str(&str) serialize_bytes(&[u8])
+	}
+
+	fn serialize_none(self) -> Result<(), Error> {
+		self.element().serialize_none()
+	}
+
+	fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+		self.element().serialize_some(value)
+	}
+
+	// Units have no fields
+	fn serialize_unit(self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), Error> {
+		self.field(variant)
+	}
+
+	fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), Error> {
+		value.serialize(self)
+	}
+
+	fn serialize_newtype_variant<T: Serialize + ?Sized>(self, _name: &'static str, _index: u32, variant: &'static str, value: &T) -> Result<(), Error> {
+		try!{self.field(variant)};
+		value.serialize(self)
+	}
+
+	fn serialize_seq(self, _len: Option<usize>) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		Ok(self.compound(true))
+	}
+
+	fn serialize_tuple(self, _len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		Ok(self.compound(false))
+	}
+
+	fn serialize_tuple_struct(self, _name: &'static str, _len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		Ok(self.compound(false))
+	}
+
+	fn serialize_tuple_variant(self, _name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		try!{self.field(variant)};
+		Ok(self.compound(false))
+	}
+
+	fn serialize_map(self, _len: Option<usize>) -> Result<Impossible<(), Error>, Error> {
+		Err(Error::Unspecified(MAP))
+	}
+
+	fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		Ok(self.compound(false))
+	}
+
+	fn serialize_struct_variant(self, _name: &'static str, _index: u32, variant: &'static str, _len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		try!{self.field(variant)};
+		Ok(self.compound(false))
+	}
+}
+
+/// Serializes the elements of a struct, tuple or sequence as fields.
+pub struct Compound<'s, 'm: 's, 'a: 'm, W: 'a + Write> {
+	ser: &'s mut Serializer<'m, 'a, W>,
+	sequence: bool,
+}
+
+impl<'s, 'm, 'a, W: Write> Compound<'s, 'm, 'a, W> {
+	fn element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+		value.serialize(FieldSerializer { ser: &mut *self.ser, in_sequence: self.sequence, in_option: false })
+	}
+
+	fn end(self) -> Result<(), Error> {
+		if self.sequence {
+			self.ser.after_sequence = true;
+		}
+		Ok(())
+	}
+}
+
+impl<'s, 'm, 'a, W: Write> ser::SerializeSeq for Compound<'s, 'm, 'a, W> {
+	type Ok = ();
+	type Error = Error;
+
+	fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+		self.element(value)
+	}
+
+	fn end(self) -> Result<(), Error> {
+		Compound::end(self)
+	}
+}
+
+impl<'s, 'm, 'a, W: Write> ser::SerializeTuple for Compound<'s, 'm, 'a, W> {
+	type Ok = ();
+	type Error = Error;
+
+	fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+		self.element(value)
+	}
+
+	fn end(self) -> Result<(), Error> {
+		Compound::end(self)
+	}
+}
+
+impl<'s, 'm, 'a, W: Write> ser::SerializeTupleStruct for Compound<'s, 'm, 'a, W> {
+	type Ok = ();
+	type Error = Error;
+
+	fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+		self.element(value)
+	}
+
+	fn end(self) -> Result<(), Error> {
+		Compound::end(self)
+	}
+}
+
+impl<'s, 'm, 'a, W: Write> ser::SerializeTupleVariant for Compound<'s, 'm, 'a, W> {
+	type Ok = ();
+	type Error = Error;
+
+	fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+		self.element(value)
+	}
+
+	fn end(self) -> Result<(), Error> {
+		Compound::end(self)
+	}
+}
+
+impl<'s, 'm, 'a, W: Write> ser::SerializeStruct for Compound<'s, 'm, 'a, W> {
+	type Ok = ();
+	type Error = Error;
+
+	fn serialize_field<T: Serialize + ?Sized>(&mut self, _key: &'static str, value: &T) -> Result<(), Error> {
+		self.element(value)
+	}
+
+	fn end(self) -> Result<(), Error> {
+		Compound::end(self)
+	}
+}
+
+impl<'s, 'm, 'a, W: Write> ser::SerializeStructVariant for Compound<'s, 'm, 'a, W> {
+	type Ok = ();
+	type Error = Error;
+
+	fn serialize_field<T: Serialize + ?Sized>(&mut self, _key: &'static str, value: &T) -> Result<(), Error> {
+		self.element(value)
+	}
+
+	fn end(self) -> Result<(), Error> {
+		Compound::end(self)
+	}
+}
+
+// Serializes an element of a struct, tuple or sequence. Elements of
+// sequences and the contents of options must fit in a single field.
+struct FieldSerializer<'s, 'm: 's, 'a: 'm, W: 'a + Write> {
+	ser: &'s mut Serializer<'m, 'a, W>,
+	in_sequence: bool,
+	in_option: bool,
+}
+
+impl<'s, 'm, 'a, W: Write> FieldSerializer<'s, 'm, 'a, W> {
+	fn field<T: ToField + ?Sized>(self, value: &T) -> Result<(), Error> {
+		self.ser.field(value)
+	}
+
+	fn flatten(self) -> Result<&'s mut Serializer<'m, 'a, W>, Error> {
+		if self.in_sequence || self.in_option {
+			return Err(Error::Unspecified(NOT_FLATTENED));
+		}
+		Ok(self.ser)
+	}
+}
+
+impl<'s, 'm, 'a, W: Write> ser::Serializer for FieldSerializer<'s, 'm, 'a, W> {
+	type Ok = ();
+	type Error = Error;
+	type SerializeSeq = Compound<'s, 'm, 'a, W>;
+	type SerializeTuple = Compound<'s, 'm, 'a, W>;
+	type SerializeTupleStruct = Compound<'s, 'm, 'a, W>;
+	type SerializeTupleVariant = Compound<'s, 'm, 'a, W>;
+	type SerializeMap = Impossible<(), Error>;
+	type SerializeStruct = Compound<'s, 'm, 'a, W>;
+	type SerializeStructVariant = Compound<'s, 'm, 'a, W>;
+
+	serialize_fields! {
+		serialize_bool(bool)
+		serialize_i8(i8) serialize_i16(i16) serialize_i32(i32) serialize_i64(i64) serialize_i128(i128)
+		serialize_u8(u8) serialize_u16(u16) serialize_u32(u32) serialize_u64(u64) serialize_u128(u128)
+		serialize_f32(f32) serialize_f64(f64)
+		serialize_char(char) serialize_str(&str) serialize_bytes(&[u8])
+	}
+
+	fn serialize_none(self) -> Result<(), Error> {
+		self.field("")
+	}
+
+	fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+		value.serialize(FieldSerializer { in_option: true, ..self })
+	}
+
+	fn serialize_unit(self) -> Result<(), Error> {
+		self.field("")
+	}
+
+	fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+		self.field("")
+	}
+
+	fn serialize_unit_variant(self, _name: &'static str, _index: u32, variant: &'static str) -> Result<(), Error> {
+		self.field(variant)
+	}
+
+	fn serialize_newtype_struct<T: Serialize + ?Sized>(self, _name: &'static str, value: &T) -> Result<(), Error> {
+		value.serialize(self)
+	}
+
+	fn serialize_newtype_variant<T: Serialize + ?Sized>(self, name: &'static str, index: u32, variant: &'static str, value: &T) -> Result<(), Error> {
+		try!{self.flatten()}.serialize_newtype_variant(name, index, variant, value)
+	}
+
+	fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		if self.in_sequence {
+			return Err(Error::Unspecified(NESTED_SEQUENCE));
+		}
+		if self.ser.after_sequence {
+			return Err(Error::Unspecified(SEQUENCE_NOT_LAST));
+		}
+		try!{self.flatten()}.serialize_seq(len)
+	}
+
+	fn serialize_tuple(self, len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		try!{self.flatten()}.serialize_tuple(len)
+	}
+
+	fn serialize_tuple_struct(self, name: &'static str, len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		try!{self.flatten()}.serialize_tuple_struct(name, len)
+	}
+
+	fn serialize_tuple_variant(self, name: &'static str, index: u32, variant: &'static str, len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		try!{self.flatten()}.serialize_tuple_variant(name, index, variant, len)
+	}
+
+	fn serialize_map(self, _len: Option<usize>) -> Result<Impossible<(), Error>, Error> {
+		Err(Error::Unspecified(MAP))
+	}
+
+	fn serialize_struct(self, name: &'static str, len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		try!{self.flatten()}.serialize_struct(name, len)
+	}
+
+	fn serialize_struct_variant(self, name: &'static str, index: u32, variant: &'static str, len: usize) -> Result<Compound<'s, 'm, 'a, W>, Error> {
+		try!{self.flatten()}.serialize_struct_variant(name, index, variant, len)
+	}
+}
+
+pub struct Deserializer<'m, 'a: 'm> {
+	source: Source<'m, 'a>,
+}
+
+// `from_owned_message` reads the fields directly, instead of encoding them
+// and parsing them back
+enum Source<'m, 'a: 'm> {
+	Parser(&'m mut pullparser::Message<'a>),
+	Owned(message::Fields<'m>),
+}
+
+impl<'m, 'a> Deserializer<'m, 'a> {
+	pub fn new(message: &'m mut pullparser::Message<'a>) -> Deserializer<'m, 'a> {
+		Deserializer { source: Source::Parser(message) }
+	}
+
+	fn read_field(&mut self) -> Result<Vec<u8>, Error> {
+		let field = match self.source {
+			Source::Parser(ref mut message) => {
+				let mut buf = Vec::new();
+				try!{message.read_field_to_end(&mut buf)}.map(|_| buf)
+			},
+			Source::Owned(ref mut fields) => fields.next().map(|field| field.to_vec()),
+		};
+		field.ok_or(Error::Unspecified("Missing field"))
+	}
+
+	fn ignore_rest(&mut self) -> Result<(), Error> {
+		match self.source {
+			Source::Parser(ref mut message) => try!{message.ignore_rest()},
+			Source::Owned(ref mut fields) => fields.for_each(drop),
+		}
+		Ok(())
+	}
+
+	fn at_end(&self) -> bool {
+		match self.source {
+			Source::Parser(ref message) => message.at_end(),
+			Source::Owned(ref fields) => fields.len() == 0,
+		}
+	}
+
+	fn element<'x>(&'x mut self) -> FieldDeserializer<'x, 'm, 'a> {
+		FieldDeserializer { de: self, in_sequence: false, field: None }
+	}
+}
+
+impl<'m> Deserializer<'m, 'static> {
+	fn owned(message: &'m OwnedMessage) -> Deserializer<'m, 'static> {
+		Deserializer { source: Source::Owned(message.iter()) }
+	}
+}
+
+macro_rules! forward_to_field {
+	($($method:ident)*) => {
+		$(
+			fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+				self.element().$method(visitor)
+			}
+		)*
+	}
+}
+
+impl<'de, 'x, 'm, 'a> de::Deserializer<'de> for &'x mut Deserializer<'m, 'a> {
+	type Error = Error;
+
+	forward_to_field! {
+		deserialize_any deserialize_bool
+		deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64 deserialize_i128
+		deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_u128
+		deserialize_f32 deserialize_f64
+		deserialize_char deserialize_str deserialize_string deserialize_bytes deserialize_byte_buf
+		deserialize_option deserialize_identifier
+	}
+
+	fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_unit()
+	}
+
+	fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_unit()
+	}
+
+	fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_newtype_struct(self)
+	}
+
+	fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_seq(RestAccess { de: self })
+	}
+
+	fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_seq(FixedAccess { de: self, remaining: len })
+	}
+
+	fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, len: usize, visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_seq(FixedAccess { de: self, remaining: len })
+	}
+
+	fn deserialize_map<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+		Err(Error::Unspecified(MAP))
+	}
+
+	fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_seq(FixedAccess { de: self, remaining: fields.len() })
+	}
+
+	fn deserialize_enum<V: Visitor<'de>>(self, _name: &'static str, _variants: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_enum(self)
+	}
+
+	fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+		try!{self.ignore_rest()};
+		visitor.visit_unit()
+	}
+}
+
+impl<'de, 'x, 'm, 'a> de::EnumAccess<'de> for &'x mut Deserializer<'m, 'a> {
+	type Error = Error;
+	type Variant = Self;
+
+	fn variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, Self), Error> {
+		let variant: String = try!{self.element().parse()};
+		let de: StringDeserializer<Error> = variant.into_deserializer();
+		let value = try!{seed.deserialize(de)};
+		Ok((value, self))
+	}
+}
+
+impl<'de, 'x, 'm, 'a> de::VariantAccess<'de> for &'x mut Deserializer<'m, 'a> {
+	type Error = Error;
+
+	fn unit_variant(self) -> Result<(), Error> {
+		Ok(())
+	}
+
+	fn newtype_variant_seed<S: DeserializeSeed<'de>>(self, seed: S) -> Result<S::Value, Error> {
+		seed.deserialize(self)
+	}
+
+	fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_seq(FixedAccess { de: self, remaining: len })
+	}
+
+	fn struct_variant<V: Visitor<'de>>(self, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_seq(FixedAccess { de: self, remaining: fields.len() })
+	}
+}
+
+// The elements of a struct or tuple
+struct FixedAccess<'x, 'm: 'x, 'a: 'm> {
+	de: &'x mut Deserializer<'m, 'a>,
+	remaining: usize,
+}
+
+impl<'de, 'x, 'm, 'a> de::SeqAccess<'de> for FixedAccess<'x, 'm, 'a> {
+	type Error = Error;
+
+	fn next_element_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, Error> {
+		if self.remaining == 0 {
+			return Ok(None);
+		}
+		self.remaining -= 1;
+		seed.deserialize(self.de.element()).map(Some)
+	}
+
+	fn size_hint(&self) -> Option<usize> {
+		Some(self.remaining)
+	}
+}
+
+// A sequence consisting of the rest of the fields in the message
+struct RestAccess<'x, 'm: 'x, 'a: 'm> {
+	de: &'x mut Deserializer<'m, 'a>,
+}
+
+impl<'de, 'x, 'm, 'a> de::SeqAccess<'de> for RestAccess<'x, 'm, 'a> {
+	type Error = Error;
+
+	fn next_element_seed<S: DeserializeSeed<'de>>(&mut self, seed: S) -> Result<Option<S::Value>, Error> {
+		if self.de.at_end() {
+			return Ok(None);
+		}
+		let element = FieldDeserializer { de: &mut *self.de, in_sequence: true, field: None };
+		seed.deserialize(element).map(Some)
+	}
+}
+
+// Deserializes an element of a struct, tuple or sequence. `field` holds a
+// field that has already been read, when looking for `None`. Elements of
+// sequences and the contents of options must fit in a single field.
+struct FieldDeserializer<'x, 'm: 'x, 'a: 'm> {
+	de: &'x mut Deserializer<'m, 'a>,
+	in_sequence: bool,
+	field: Option<Vec<u8>>,
+}
+
+impl<'x, 'm, 'a> FieldDeserializer<'x, 'm, 'a> {
+	fn read(&mut self) -> Result<Vec<u8>, Error> {
+		match self.field.take() {
+			Some(field) => Ok(field),
+			None => self.de.read_field(),
+		}
+	}
+
+	fn parse<T: FromField>(mut self) -> Result<T, Error> {
+		let field = try!{self.read()};
+		Ok(try!{T::from_field(&field)})
+	}
+
+	fn flatten(self) -> Result<&'x mut Deserializer<'m, 'a>, Error> {
+		if self.in_sequence || self.field.is_some() {
+			return Err(Error::Unspecified(NOT_FLATTENED));
+		}
+		Ok(self.de)
+	}
+}
+
+macro_rules! deserialize_fields {
+	($($method:ident => $visit:ident($t:ty))*) => {
+		$(
+			fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+				let value: $t = try!{self.parse()};
+				visitor.$visit(value)
+			}
+		)*
+	}
+}
+
+impl<'de, 'x, 'm, 'a> de::Deserializer<'de> for FieldDeserializer<'x, 'm, 'a> {
+	type Error = Error;
+
+	deserialize_fields! {
+		deserialize_bool => visit_bool(bool)
+		deserialize_i8 => visit_i8(i8)
+		deserialize_i16 => visit_i16(i16)
+		deserialize_i32 => visit_i32(i32)
+		deserialize_i64 => visit_i64(i64)
+		deserialize_i128 => visit_i128(i128)
+		deserialize_u8 => visit_u8(u8)
+		deserialize_u16 => visit_u16(u16)
+		deserialize_u32 => visit_u32(u32)
+		deserialize_u64 => visit_u64(u64)
+		deserialize_u128 => visit_u128(u128)
+		deserialize_f32 => visit_f32(f32)
+		deserialize_f64 => visit_f64(f64)
+		deserialize_char => visit_char(char)
+		deserialize_str => visit_string(String)
+		deserialize_string => visit_string(String)
+		deserialize_identifier => visit_string(String)
+		deserialize_bytes => visit_byte_buf(Vec<u8>)
+		deserialize_byte_buf => visit_byte_buf(Vec<u8>)
+	}
+
+	// Fields are self-describing only as far as text or not
+	fn deserialize_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
+		match String::from_utf8(try!{self.read()}) {
+			Ok(text) => visitor.visit_string(text),
+			Err(err) => visitor.visit_byte_buf(err.into_bytes()),
+		}
+	}
+
+	fn deserialize_option<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
+		if self.field.is_none() && self.de.at_end() {
+			return visitor.visit_none();
+		}
+		let field = try!{self.read()};
+		if field.is_empty() {
+			visitor.visit_none()
+		} else {
+			self.field = Some(field);
+			visitor.visit_some(self)
+		}
+	}
+
+	fn deserialize_unit<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
+		try!{self.read()};
+		visitor.visit_unit()
+	}
+
+	fn deserialize_unit_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+		self.deserialize_unit(visitor)
+	}
+
+	fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Error> {
+		visitor.visit_newtype_struct(self)
+	}
+
+	fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+		if self.in_sequence {
+			return Err(Error::Unspecified(NESTED_SEQUENCE));
+		}
+		try!{self.flatten()}.deserialize_seq(visitor)
+	}
+
+	fn deserialize_tuple<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, Error> {
+		try!{self.flatten()}.deserialize_tuple(len, visitor)
+	}
+
+	fn deserialize_tuple_struct<V: Visitor<'de>>(self, name: &'static str, len: usize, visitor: V) -> Result<V::Value, Error> {
+		try!{self.flatten()}.deserialize_tuple_struct(name, len, visitor)
+	}
+
+	fn deserialize_map<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Error> {
+		Err(Error::Unspecified(MAP))
+	}
+
+	fn deserialize_struct<V: Visitor<'de>>(self, name: &'static str, fields: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+		try!{self.flatten()}.deserialize_struct(name, fields, visitor)
+	}
+
+	// Only unit variants fit in a single field
+	fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str], visitor: V) -> Result<V::Value, Error> {
+		if self.in_sequence || self.field.is_some() {
+			let variant: String = try!{self.parse()};
+			let de: StringDeserializer<Error> = variant.into_deserializer();
+			return visitor.visit_enum(de);
+		}
+		self.de.deserialize_enum(name, variants, visitor)
+	}
+
+	fn deserialize_ignored_any<V: Visitor<'de>>(mut self, visitor: V) -> Result<V::Value, Error> {
+		try!{self.read()};
+		visitor.visit_unit()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::Cursor;
+	use serialization::*;
+
+	#[derive(Debug, PartialEq, Serialize, Deserialize)]
+	struct Login {
+		id: u32,
+		user: String,
+		password: Option<String>,
+	}
+
+	#[derive(Debug, PartialEq, Serialize, Deserialize)]
+	enum Request {
+		Ping,
+		Protocol(String),
+		Get(u32, String),
+		Set { key: String, value: Option<i64> },
+		Login(Login),
+		Mget(Vec<String>),
+	}
+
+	#[derive(Debug, PartialEq, Serialize, Deserialize)]
+	struct Tagged(u32, Request);
+
+	#[derive(Debug, PartialEq, Serialize, Deserialize)]
+	enum Color { Red, Green }
+
+	#[derive(Debug, PartialEq, Serialize, Deserialize)]
+	struct Paint(Color, f64, bool, char);
+
+	fn round_trip<T: Serialize + DeserializeOwned>(value: &T, expected: &str) -> T {
+		let message = to_owned_message(value).unwrap();
+		assert_message!(expected.parse::<OwnedMessage>().unwrap(), message);
+		from_owned_message(&message).unwrap()
+	}
+
+	#[test]
+	fn it_maps_structs_to_fields() {
+		let login = Login { id: 7, user: "katt ape".into(), password: None };
+		assert_eq!(login, round_trip(&login, "7 {8}katt ape {0}"));
+
+		let paint = Paint(Color::Green, 1.5, true, '{');
+		assert_eq!(paint, round_trip(&paint, "Green 1.5 true {1}{"));
+
+		let tuple = (1u8, "a".to_string(), ());
+		assert_eq!(tuple, round_trip(&tuple, "1 a {0}"));
+	}
+
+	#[test]
+	fn it_maps_enums_to_a_leading_variant_name() {
+		let cases = vec![
+			(Request::Ping, "Ping"),
+			(Request::Protocol("lol".into()), "Protocol lol"),
+			(Request::Get(3, "x".into()), "Get 3 x"),
+			(Request::Set { key: "k".into(), value: Some(-1) }, "Set k -1"),
+			(Request::Login(Login { id: 1, user: "u".into(), password: Some("p".into()) }), "Login 1 u p"),
+			(Request::Mget(vec!["a".into(), "b c".into()]), "Mget a {3}b c"),
+			(Request::Mget(vec![]), "Mget"),
+		];
+		for (request, expected) in cases {
+			assert_eq!(request, round_trip(&request, expected));
+		}
+
+		let tagged = Tagged(4, Request::Get(5, "y".into()));
+		assert_eq!(tagged, round_trip(&tagged, "4 Get 5 y"));
+
+		let tagged = Tagged(4, Request::Mget(vec!["z".into()]));
+		assert_eq!(tagged, round_trip(&tagged, "4 Mget z"));
+	}
+
+	#[test]
+	fn it_converts_messages_with_no_fields_or_only_empty_ones() {
+		assert_eq!(0, to_owned_message(&()).unwrap().len());
+		from_owned_message::<()>(&OwnedMessage::new()).unwrap();
+
+		let empty = Vec::<u32>::new();
+		let message = to_owned_message(&empty).unwrap();
+		assert_eq!(0, message.len());
+		assert_eq!(empty, from_owned_message::<Vec<u32>>(&message).unwrap());
+
+		let value = ("".to_string(),);
+		let message = to_owned_message(&value).unwrap();
+		assert_eq!(vec![Vec::<u8>::new()], Vec::<Vec<u8>>::from(message.clone()));
+		assert_eq!(value, from_owned_message(&message).unwrap());
+	}
+
+	#[test]
+	fn it_maps_a_trailing_sequence_to_the_rest_of_the_fields() {
+		let value = (1u32, vec![2u32, 3, 4]);
+		assert_eq!(value, round_trip(&value, "1 2 3 4"));
+
+		let value = vec![Some(1u32), None, Some(2)];
+		assert_eq!(value, round_trip(&value, "1 {0} 2"));
+	}
+
+	#[test]
+	fn it_treats_missing_trailing_options_as_none() {
+		let message: OwnedMessage = "7 u".parse().unwrap();
+		let login: Login = from_owned_message(&message).unwrap();
+		assert_eq!(Login { id: 7, user: "u".into(), password: None }, login);
+
+		let message: OwnedMessage = "7".parse().unwrap();
+		assert!(from_owned_message::<Login>(&message).is_err());
+	}
+
+	#[test]
+	fn it_flattens_nested_structs_and_tuples() {
+		let value = (1u32, (2u32, 3u32), Login { id: 4, user: "u".into(), password: None });
+		assert_eq!(value, round_trip(&value, "1 2 3 4 u {0}"));
+	}
+
+	#[test]
+	fn it_rejects_what_can_not_be_flattened() {
+		assert!(to_owned_message(&(vec![1], 2)).is_err());
+		assert!(to_owned_message(&(1, vec![vec![2]])).is_err());
+		assert!(to_owned_message(&(1, vec![(2, 3)])).is_err());
+		assert!(to_owned_message(&(1, Some((2, 3)))).is_err());
+		assert!(to_owned_message(&(1, vec![Request::Get(3, "x".into())])).is_err());
+		assert!(to_owned_message(&::std::collections::HashMap::<u32, u32>::new()).is_err());
+
+		let message: OwnedMessage = "1 2 3".parse().unwrap();
+		assert!(from_owned_message::<(u32, Vec<(u32, u32)>)>(&message).is_err());
+		assert!(from_owned_message::<(u32, Option<(u32, u32)>)>(&message).is_err());
+		assert!(from_owned_message::<(u32, u32)>(&message).is_err());
+		assert!(from_owned_message::<(u32, u32, String, u32)>(&message).is_err());
+
+		let message: OwnedMessage = "Blue".parse().unwrap();
+		assert!(from_owned_message::<Color>(&message).is_err());
+	}
+
+	#[test]
+	fn it_writes_nothing_on_failure() {
+		let mut buf = Vec::new();
+		{
+			let mut generator = PushGenerator::new(&mut buf);
+			to_generator(&mut generator, &(1, "a")).unwrap();
+			assert!(to_generator(&mut generator, &(2, Some((3, 4)))).is_err());
+			to_generator(&mut generator, &Request::Ping).unwrap();
+		}
+		assert_eq!(&b"1 a\nPing\n"[..], &buf[..]);
+
+		let mut parser = PullParser::new(Cursor::new(buf));
+		assert_eq!(Some((1, "a".to_string())), from_parser(&mut parser).unwrap());
+		assert_eq!(Some(Request::Ping), from_parser(&mut parser).unwrap());
+		assert_eq!(None, from_parser::<_, Request>(&mut parser).unwrap());
+	}
+}
@@ -0,0 +1,443 @@
+/*!
+A generator that can be shared between threads. Each message is generated in
+memory by the thread that writes it, and then written whole by a writer
+thread, so messages from different threads never interleave.
+
+Messages are queued for the writer thread in a queue of limited capacity.
+When the writer is slow and the queue is full, writing blocks until there is
+room, so producers can not run arbitrarily far ahead of the peer.
+
+Messages are written in the order they are queued, so the messages written
+by one thread are written in that order. To fix the position of a message
+before it is generated, reserve a `Slot` for it. The messages queued after
+an unfilled slot wait in the queue, so a thread holding a slot must fill or
+drop it before it writes more than fit in the queue, or flushes, which both
+wait for the slot.
+*/
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread;
+
+use pushgenerator::{self, PushGenerator};
+
+/// A message being generated for a `SharedGenerator`.
+pub type LocalMessage<'a, 'b> = pushgenerator::Message<'a, &'b mut Vec<u8>>;
+
+const WRITER_FAILED: &'static str = "The writer has failed";
+
+enum Command {
+	Write(Vec<u8>),
+	// Fills the position of a slot that was dropped without writing
+	Skip,
+	Flush(mpsc::Sender<Result<(), pushgenerator::Error>>),
+}
+
+/// A handle to a writer thread. It can be cloned and passed to other
+/// threads. The writer thread stops when all handles and slots are dropped,
+/// or when writing fails, after which writing fails.
+#[derive(Clone)]
+pub struct SharedGenerator {
+	inner: Arc<Inner>,
+}
+
+struct Inner {
+	// The position of the next message. It is locked while sending, so that
+	// messages are queued in the order of their positions.
+	next_position: Mutex<u64>,
+	sender: mpsc::Sender<(u64, Command)>,
+	room: Arc<Room>,
+}
+
+// The room left in the queue. Each queued message, including those held
+// back by the writer thread, takes up room until the writer thread gets to
+// it. Slots take up room from when they are reserved, so that filling them
+// never waits.
+struct Room {
+	left: Mutex<Option<usize>>,
+	changed: Condvar,
+}
+
+impl Room {
+	fn take(&self) -> Result<(), pushgenerator::Error> {
+		let mut left = self.left.lock().unwrap();
+		loop {
+			match *left {
+				Some(0) => left = self.changed.wait(left).unwrap(),
+				Some(ref mut left) => {
+					*left -= 1;
+					return Ok(());
+				},
+				None => return Err(pushgenerator::Error::Unspecified(WRITER_FAILED)),
+			}
+		}
+	}
+
+	fn give_back(&self) {
+		if let Some(ref mut left) = *self.left.lock().unwrap() {
+			*left += 1;
+		}
+		self.changed.notify_one();
+	}
+
+	// The writer thread has stopped, so waiting for room would never end
+	fn close(&self) {
+		*self.left.lock().unwrap() = None;
+		self.changed.notify_all();
+	}
+}
+
+impl SharedGenerator {
+	/// Start a writer thread writing to `writer`, with room for `capacity`
+	/// messages in the queue, or one if `capacity` is 0.
+	pub fn new<W: Write + Send + 'static>(writer: W, capacity: usize) -> SharedGenerator {
+		let (sender, receiver) = mpsc::channel();
+		let room = Arc::new(Room {
+			left: Mutex::new(Some(cmp::max(capacity, 1))),
+			changed: Condvar::new(),
+		});
+		{
+			let room = room.clone();
+			thread::spawn(move || {
+				write_messages(writer, receiver, &room);
+				room.close();
+			});
+		}
+		SharedGenerator {
+			inner: Arc::new(Inner {
+				next_position: Mutex::new(0),
+				sender: sender,
+				room: room,
+			}),
+		}
+	}
+
+	/// Write a message with the fields written by `write_fields`. Nothing is
+	/// written if `write_fields` fails.
+	pub fn write_with<F>(&self, write_fields: F) -> Result<(), pushgenerator::Error>
+		where F: FnOnce(&mut LocalMessage) -> Result<(), pushgenerator::Error>
+	{
+		let message = try!{generate(write_fields)};
+		self.queue(Command::Write(message))
+	}
+
+	pub fn write_message(&self, fields: &[&[u8]]) -> Result<(), pushgenerator::Error> {
+		self.write_with(|message| {
+			for field in fields {
+				try!{message.write_field(field)};
+			}
+			Ok(())
+		})
+	}
+
+	/// Reserve the position after the messages queued so far for a message
+	/// that is written later. Messages queued after it are held back until
+	/// it is written or dropped, so it should not be held for long. This
+	/// waits for room in the queue, like writing a message, and fails if the
+	/// writer has failed.
+	pub fn reserve(&self) -> Result<Slot, pushgenerator::Error> {
+		try!{self.inner.room.take()};
+		let mut next_position = self.inner.next_position.lock().unwrap();
+		let position = *next_position;
+		*next_position += 1;
+		Ok(Slot {
+			generator: self.clone(),
+			position: position,
+			filled: false,
+		})
+	}
+
+	/// Wait until the messages queued so far have been written and the
+	/// writer has been flushed. This never returns if the calling thread
+	/// holds an unfilled `Slot`.
+	pub fn flush(&self) -> Result<(), pushgenerator::Error> {
+		let (sender, receiver) = mpsc::channel();
+		try!{self.queue(Command::Flush(sender))};
+		match receiver.recv() {
+			Ok(result) => result,
+			Err(_) => Err(pushgenerator::Error::Unspecified(WRITER_FAILED)),
+		}
+	}
+
+	fn queue(&self, command: Command) -> Result<(), pushgenerator::Error> {
+		try!{self.inner.room.take()};
+		let mut next_position = self.inner.next_position.lock().unwrap();
+		try!{self.send(*next_position, command)};
+		*next_position += 1;
+		Ok(())
+	}
+
+	fn send(&self, position: u64, command: Command) -> Result<(), pushgenerator::Error> {
+		self.inner.sender.send((position, command))
+			.map_err(|_| pushgenerator::Error::Unspecified(WRITER_FAILED))
+	}
+}
+
+/// A position reserved with `SharedGenerator::reserve`.
+pub struct Slot {
+	generator: SharedGenerator,
+	position: u64,
+	filled: bool,
+}
+
+impl Slot {
+	/// Write a message in this position, with the fields written by
+	/// `write_fields`. Nothing is written if `write_fields` fails.
+	pub fn write_with<F>(mut self, write_fields: F) -> Result<(), pushgenerator::Error>
+		where F: FnOnce(&mut LocalMessage) -> Result<(), pushgenerator::Error>
+	{
+		let message = try!{generate(write_fields)};
+		self.filled = true;
+		self.generator.send(self.position, Command::Write(message))
+	}
+
+	pub fn write_message(self, fields: &[&[u8]]) -> Result<(), pushgenerator::Error> {
+		self.write_with(|message| {
+			for field in fields {
+				try!{message.write_field(field)};
+			}
+			Ok(())
+		})
+	}
+}
+
+impl Drop for Slot {
+	fn drop(&mut self) {
+		if !self.filled {
+			let _ = self.generator.send(self.position, Command::Skip);
+		}
+	}
+}
+
+fn generate<F>(write_fields: F) -> Result<Vec<u8>, pushgenerator::Error>
+	where F: FnOnce(&mut LocalMessage) -> Result<(), pushgenerator::Error>
+{
+	let mut buffer = Vec::new();
+	{
+		let mut generator = PushGenerator::new(&mut buffer);
+		let mut message = try!{generator.next_buffered_message()};
+		try!{write_fields(&mut message)};
+		try!{message.commit()};
+	}
+	Ok(buffer)
+}
+
+// The writer thread. Commands may arrive out of order, when slots are
+// written, so they are held back until all earlier positions are filled.
+fn write_messages<W: Write>(mut writer: W, receiver: mpsc::Receiver<(u64, Command)>, room: &Room) {
+	let mut next_position = 0;
+	let mut held_back = BTreeMap::new();
+	for (position, command) in receiver {
+		held_back.insert(position, command);
+		while let Some(command) = held_back.remove(&next_position) {
+			next_position += 1;
+			room.give_back();
+			match command {
+				Command::Write(message) => {
+					if writer.write_all(&message).is_err() {
+						return;
+					}
+				},
+				Command::Skip => (),
+				Command::Flush(sender) => {
+					let result = writer.flush().map_err(pushgenerator::Error::from);
+					let failed = result.is_err();
+					if failed {
+						// Fail anything that comes after the failed flush
+						room.close();
+					}
+					let _ = sender.send(result);
+					if failed {
+						return;
+					}
+				},
+			}
+		}
+	}
+	let _ = writer.flush();
+}
+
+#[cfg(test)]
+mod test {
+	use std::io::{self, Write};
+	use std::sync::{mpsc, Arc, Mutex};
+	use std::thread;
+	use sharedgenerator::*;
+
+	// Collects the written data where the test can see it
+	#[derive(Clone, Default)]
+	struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+	impl Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	impl SharedBuffer {
+		fn contents(&self) -> Vec<u8> {
+			self.0.lock().unwrap().clone()
+		}
+	}
+
+	// Blocks each write until it is let through
+	struct GatedWriter(mpsc::Receiver<()>);
+
+	impl Write for GatedWriter {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			match self.0.recv() {
+				Ok(()) => Ok(buf.len()),
+				Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")),
+			}
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn it_is_send_and_sync() {
+		fn assert_send_sync<T: Send + Sync>() {}
+		assert_send_sync::<SharedGenerator>();
+		assert_send_sync::<Slot>();
+	}
+
+	#[test]
+	fn it_writes_whole_messages_from_many_threads() {
+		let buffer = SharedBuffer::default();
+		let generator = SharedGenerator::new(buffer.clone(), 4);
+
+		let threads: Vec<_> = (0..8).map(|i| {
+			let generator = generator.clone();
+			thread::spawn(move || {
+				for j in 0..50 {
+					generator.write_with(|message| {
+						try!{message.write(&i)};
+						try!{message.write_field(b"a b")};
+						message.write(&j)
+					}).unwrap();
+				}
+			})
+		}).collect();
+		for thread in threads {
+			thread.join().unwrap();
+		}
+		generator.flush().unwrap();
+
+		let contents = buffer.contents();
+		let mut next = vec![0; 8];
+		for line in contents.split(|&b| b == b'\n').filter(|line| !line.is_empty()) {
+			let line = ::std::str::from_utf8(line).unwrap();
+			let fields: Vec<&str> = line.split(" {3}a b ").collect();
+			assert_eq!(2, fields.len(), "{}", line);
+			let i: usize = fields[0].parse().unwrap();
+			assert_eq!(next[i].to_string(), fields[1]);
+			next[i] += 1;
+		}
+		assert_eq!(vec![50; 8], next);
+	}
+
+	#[test]
+	fn it_writes_reserved_slots_in_order() {
+		let buffer = SharedBuffer::default();
+		let generator = SharedGenerator::new(buffer.clone(), 4);
+
+		let first = generator.reserve().unwrap();
+		let skipped = generator.reserve().unwrap();
+		generator.write_message(&[b"third"]).unwrap();
+		let writer = thread::spawn(move || first.write_message(&[b"first"]).unwrap());
+		drop(skipped);
+		writer.join().unwrap();
+		assert!(generator.reserve().unwrap().write_with(|_| Err(pushgenerator::Error::Unspecified("failed"))).is_err());
+		generator.write_message(&[b"fourth", b""]).unwrap();
+		generator.flush().unwrap();
+
+		assert_eq!(&b"first\nthird\nfourth {0}\n"[..], &buffer.contents()[..]);
+	}
+
+	#[test]
+	fn it_blocks_when_the_writer_is_slow() {
+		let (gate, gate_receiver) = mpsc::channel();
+		let generator = SharedGenerator::new(GatedWriter(gate_receiver), 1);
+		let (written, written_receiver) = mpsc::channel();
+
+		let producer = {
+			let generator = generator.clone();
+			thread::spawn(move || {
+				for i in 0..4 {
+					generator.write_message(&[i.to_string().as_bytes()]).unwrap();
+					written.send(i).unwrap();
+				}
+			})
+		};
+
+		// One message is being written and one is queued, which leaves no
+		// room for the next one until the first is let through
+		let queued: Vec<i32> = written_receiver.iter().take(2).collect();
+		assert_eq!(vec![0, 1], queued);
+		assert_eq!(Some(0), *generator.inner.room.left.lock().unwrap());
+		assert!(written_receiver.try_recv().is_err());
+
+		for _ in 0..4 {
+			gate.send(()).unwrap();
+		}
+		producer.join().unwrap();
+		generator.flush().unwrap();
+
+		drop(gate);
+		generator.write_message(&[b"x"]).unwrap();
+		assert!(generator.flush().is_err());
+		assert!(generator.write_message(&[b"x"]).is_err());
+		assert!(generator.reserve().is_err());
+	}
+
+	#[test]
+	fn it_blocks_behind_an_unfilled_slot() {
+		let buffer = SharedBuffer::default();
+		let generator = SharedGenerator::new(buffer.clone(), 2);
+		let slot = generator.reserve().unwrap();
+		let (written, written_receiver) = mpsc::channel();
+
+		let producer = {
+			let generator = generator.clone();
+			thread::spawn(move || {
+				for i in 0..4 {
+					generator.write_message(&[i.to_string().as_bytes()]).unwrap();
+					written.send(i).unwrap();
+				}
+			})
+		};
+
+		// The slot and one message fill the queue, and the writer thread
+		// holds the message back until the slot is filled
+		assert_eq!(0, written_receiver.recv().unwrap());
+		assert_eq!(Some(0), *generator.inner.room.left.lock().unwrap());
+		assert!(written_receiver.try_recv().is_err());
+		assert!(buffer.contents().is_empty());
+
+		slot.write_message(&[b"slot"]).unwrap();
+		producer.join().unwrap();
+		generator.flush().unwrap();
+		assert_eq!(&b"slot\n0\n1\n2\n3\n"[..], &buffer.contents()[..]);
+	}
+
+	#[test]
+	fn it_can_be_read_by_a_parser() {
+		let buffer = SharedBuffer::default();
+		let generator = SharedGenerator::new(buffer.clone(), 1);
+		generator.write_message(&[b"a", b"{b}"]).unwrap();
+		generator.flush().unwrap();
+
+		let data = buffer.contents();
+		let mut parser = ::pullparser::PullParser::new(&data[..]);
+		assert_eq!(Some(vec![b"a".to_vec(), b"{b}".to_vec()]), parser.read_message().unwrap());
+	}
+}